@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+
+/// A decoded bencode value. Strings are kept as raw bytes, so binary fields like `pieces`
+/// or compact peer lists survive a decode/encode round trip untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Bencode {
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<Bencode>),
+    /// Keys are ordered by their raw bytes, which is the order bencode requires.
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    /// Builds a dict from string keys.
+    pub(crate) fn dict<const N: usize>(entries: [(&str, Bencode); N]) -> Self {
+        Bencode::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    /// The value under `key`, if this is a dict that has it.
+    pub(crate) fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_int(&self) -> Option<i64> {
+        match self {
+            Bencode::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Renders the value as JSON for display. Byte strings that aren't valid UTF-8 are
+    /// converted lossily, so this is not meant to be decoded back.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        match self {
+            Bencode::Bytes(bytes) => {
+                serde_json::Value::String(String::from_utf8_lossy(bytes).into_owned())
+            }
+            Bencode::Int(n) => serde_json::Value::Number((*n).into()),
+            Bencode::List(list) => {
+                serde_json::Value::Array(list.iter().map(Bencode::to_json).collect())
+            }
+            Bencode::Dict(dict) => serde_json::Value::Object(
+                dict.iter()
+                    .map(|(key, value)| {
+                        (String::from_utf8_lossy(key).into_owned(), value.to_json())
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{watch, Notify};
+
+use crate::random::random_index;
+
+/// Choker settings. The defaults follow the reference BitTorrent client.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChokerConfig {
+    /// Peers unchoked for their transfer rate, on top of the optimistic unchoke.
+    pub(crate) regular_slots: usize,
+    /// Time between two scheduled rounds.
+    pub(crate) round_interval: Duration,
+    /// Scheduled rounds between two rotations of the optimistic unchoke.
+    pub(crate) optimistic_rounds: u32,
+    /// How long a peer may send us nothing before it counts as snubbing us.
+    pub(crate) snub_timeout: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            regular_slots: 3,
+            round_interval: Duration::from_secs(10),
+            optimistic_rounds: 3,
+            snub_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What the regular slots reward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ranking {
+    /// While downloading: the peers we download from the fastest, tit-for-tat. Peers that
+    /// snub us only get a chance through the optimistic unchoke.
+    DownloadRate,
+    /// When seeding: the peers that take data from us the fastest.
+    UploadRate,
+}
+
+/// What the choker knows about a peer at the start of a round.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerRates {
+    pub(crate) id: u64,
+    pub(crate) interested: bool,
+    /// Bytes per second sent to the peer.
+    pub(crate) upload_rate: u64,
+    /// Bytes per second received from the peer.
+    pub(crate) download_rate: u64,
+    /// Whether the peer sent us nothing for `snub_timeout`.
+    pub(crate) snubbed: bool,
+}
+
+/// Tit-for-tat choking: the regular slots go to the best peers by `Ranking`, and one more
+/// peer is unchoked optimistically so new peers get a chance to prove themselves.
+pub(crate) struct Choker {
+    config: ChokerConfig,
+    ranking: Ranking,
+    rounds_until_rotation: u32,
+    optimistic: Option<u64>,
+}
+
+impl Choker {
+    pub(crate) fn new(config: ChokerConfig, ranking: Ranking) -> Self {
+        Self {
+            config,
+            ranking,
+            rounds_until_rotation: config.optimistic_rounds,
+            optimistic: None,
+        }
+    }
+
+    /// Returns the peers to unchoke. `scheduled` rounds advance the optimistic unchoke
+    /// rotation, unscheduled ones only fill slots that became free.
+    pub(crate) fn unchoked(&mut self, peers: &[PeerRates], scheduled: bool) -> HashSet<u64> {
+        let mut candidates: Vec<&PeerRates> = peers
+            .iter()
+            .filter(|peer| peer.interested)
+            .filter(|peer| !(self.ranking == Ranking::DownloadRate && peer.snubbed))
+            .collect();
+        candidates.sort_by_key(|peer| {
+            Reverse(match self.ranking {
+                Ranking::DownloadRate => peer.download_rate,
+                Ranking::UploadRate => peer.upload_rate,
+            })
+        });
+        let mut unchoked: HashSet<u64> = candidates
+            .iter()
+            .take(self.config.regular_slots)
+            .map(|peer| peer.id)
+            .collect();
+
+        if scheduled {
+            if self.rounds_until_rotation == 0 {
+                self.optimistic = None;
+                self.rounds_until_rotation = self.config.optimistic_rounds;
+            }
+            self.rounds_until_rotation = self.rounds_until_rotation.saturating_sub(1);
+        }
+
+        let eligible = |peer: &&PeerRates| peer.interested && !unchoked.contains(&peer.id);
+        let keep_optimistic = self
+            .optimistic
+            .is_some_and(|id| peers.iter().filter(eligible).any(|peer| peer.id == id));
+        if !keep_optimistic {
+            let choices: Vec<u64> = peers.iter().filter(eligible).map(|peer| peer.id).collect();
+            self.optimistic = (!choices.is_empty()).then(|| choices[random_index(choices.len())]);
+        }
+
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+/// Transfer counters of a connection, kept up to date by the connection for its choker.
+#[derive(Default)]
+struct TransferStats {
+    /// Whether the peer wants our pieces.
+    interested: AtomicBool,
+    /// Bytes of piece data sent to the peer.
+    uploaded: AtomicU64,
+    /// Bytes of piece data received from the peer.
+    downloaded: AtomicU64,
+}
+
+struct ChokedPeer {
+    stats: Arc<TransferStats>,
+    /// The choker's decision for the connection, `true` to choke.
+    choke: watch::Sender<bool>,
+}
+
+/// Transfer totals at the last scheduled round, used to derive rates.
+struct PeerHistory {
+    uploaded: u64,
+    downloaded: u64,
+    upload_rate: u64,
+    download_rate: u64,
+    /// The downloaded total when it last grew, and when that was seen.
+    received: u64,
+    last_received: Instant,
+}
+
+/// What the choker carries from one round to the next.
+struct Rounds {
+    choker: Choker,
+    history: HashMap<u64, PeerHistory>,
+    last_round: Instant,
+}
+
+/// The connections of one torrent, and the choker deciding which of them we upload to.
+pub(crate) struct ChokedPeers {
+    config: ChokerConfig,
+    peers: Mutex<HashMap<u64, ChokedPeer>>,
+    next_id: AtomicU64,
+    /// Woken when a peer leaves or changes interest, to fill free slots early.
+    changed: Notify,
+}
+
+impl ChokedPeers {
+    pub(crate) fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            peers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Puts a connection under the choker. It stays choked until a round unchokes it.
+    pub(crate) fn join(self: &Arc<Self>) -> ChokeSlot {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(TransferStats::default());
+        let (choke, decisions) = watch::channel(true);
+        let peer = ChokedPeer {
+            stats: Arc::clone(&stats),
+            choke,
+        };
+        self.peers.lock().unwrap().insert(id, peer);
+        ChokeSlot {
+            id,
+            peers: Arc::clone(self),
+            stats,
+            decisions,
+        }
+    }
+
+    /// Runs a choker round every `round_interval`, and an unscheduled one whenever a peer
+    /// leaves or changes interest.
+    pub(crate) async fn run(&self, ranking: Ranking) {
+        let mut rounds = self.rounds(ranking);
+        let mut schedule = tokio::time::interval(self.config.round_interval);
+        loop {
+            let scheduled = tokio::select! {
+                _ = schedule.tick() => true,
+                _ = self.changed.notified() => false,
+            };
+            self.round(&mut rounds, scheduled);
+        }
+    }
+
+    fn rounds(&self, ranking: Ranking) -> Rounds {
+        Rounds {
+            choker: Choker::new(self.config, ranking),
+            history: HashMap::new(),
+            last_round: Instant::now(),
+        }
+    }
+
+    fn round(&self, rounds: &mut Rounds, scheduled: bool) {
+        let peers = self.peers.lock().unwrap();
+        rounds.history.retain(|id, _| peers.contains_key(id));
+
+        let now = Instant::now();
+        let elapsed = now
+            .duration_since(rounds.last_round)
+            .as_secs_f64()
+            .max(0.001);
+        if scheduled {
+            rounds.last_round = now;
+        }
+
+        let mut rates = Vec::new();
+        for (&id, peer) in peers.iter() {
+            let uploaded = peer.stats.uploaded.load(Ordering::Relaxed);
+            let downloaded = peer.stats.downloaded.load(Ordering::Relaxed);
+            let entry = rounds.history.entry(id).or_insert(PeerHistory {
+                uploaded,
+                downloaded,
+                upload_rate: 0,
+                download_rate: 0,
+                received: downloaded,
+                last_received: now,
+            });
+            if scheduled {
+                entry.upload_rate = ((uploaded - entry.uploaded) as f64 / elapsed) as u64;
+                entry.download_rate = ((downloaded - entry.downloaded) as f64 / elapsed) as u64;
+                entry.uploaded = uploaded;
+                entry.downloaded = downloaded;
+            }
+            if downloaded != entry.received {
+                entry.received = downloaded;
+                entry.last_received = now;
+            }
+
+            rates.push(PeerRates {
+                id,
+                interested: peer.stats.interested.load(Ordering::Relaxed),
+                upload_rate: entry.upload_rate,
+                download_rate: entry.download_rate,
+                snubbed: now.duration_since(entry.last_received) >= self.config.snub_timeout,
+            });
+        }
+        rates.sort_by_key(|rate| rate.id);
+
+        let unchoked = rounds.choker.unchoked(&rates, scheduled);
+        for rate in &rates {
+            let choke = !unchoked.contains(&rate.id);
+            peers[&rate.id].choke.send_if_modified(|choked| {
+                let modified = *choked != choke;
+                *choked = choke;
+                modified
+            });
+        }
+    }
+}
+
+/// A connection's place under `ChokedPeers`: it reports its transfers through it and gets
+/// the choker's decisions from it. Dropping it takes the connection out.
+pub(crate) struct ChokeSlot {
+    id: u64,
+    peers: Arc<ChokedPeers>,
+    stats: Arc<TransferStats>,
+    decisions: watch::Receiver<bool>,
+}
+
+impl ChokeSlot {
+    /// Waits for the choker to change its decision and returns it, `true` to choke. Safe to
+    /// cancel.
+    pub(crate) async fn decision(&mut self) -> bool {
+        if self.decisions.changed().await.is_err() {
+            // The sender is only dropped along with the slot.
+            std::future::pending::<()>().await;
+        }
+        *self.decisions.borrow_and_update()
+    }
+
+    pub(crate) fn set_interested(&self, interested: bool) {
+        if self.stats.interested.swap(interested, Ordering::Relaxed) != interested {
+            self.peers.changed.notify_one();
+        }
+    }
+
+    pub(crate) fn add_uploaded(&self, bytes: u64) {
+        self.stats.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_downloaded(&self, bytes: u64) {
+        self.stats.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ChokeSlot {
+    fn drop(&mut self) {
+        self.peers.peers.lock().unwrap().remove(&self.id);
+        self.peers.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: u64, upload_rate: u64) -> PeerRates {
+        PeerRates {
+            id,
+            interested: true,
+            upload_rate,
+            download_rate: 0,
+            snubbed: false,
+        }
+    }
+
+    fn config(regular_slots: usize) -> ChokerConfig {
+        ChokerConfig {
+            regular_slots,
+            ..ChokerConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_regular_slots_go_to_fastest_peers() {
+        let mut choker = Choker::new(config(2), Ranking::UploadRate);
+        let peers = [peer(1, 300), peer(2, 100), peer(3, 200)];
+
+        let unchoked = choker.unchoked(&peers, true);
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&1) && unchoked.contains(&3));
+    }
+
+    #[test]
+    fn test_uninterested_peers_are_not_unchoked() {
+        let mut choker = Choker::new(config(1), Ranking::UploadRate);
+        let mut uninterested = peer(1, 100);
+        uninterested.interested = false;
+        let peers = [uninterested, peer(2, 10), peer(3, 0)];
+
+        let unchoked = choker.unchoked(&peers, true);
+        // Peer 2 gets the regular slot and peer 3 the optimistic one.
+        assert_eq!(unchoked, HashSet::from([2, 3]));
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_rotates_on_schedule() {
+        let mut choker = Choker::new(
+            ChokerConfig {
+                regular_slots: 0,
+                optimistic_rounds: 3,
+                ..ChokerConfig::default()
+            },
+            Ranking::UploadRate,
+        );
+        let peers: Vec<PeerRates> = (0..50).map(|id| peer(id, 0)).collect();
+
+        let first = choker.unchoked(&peers, true);
+        assert_eq!(first.len(), 1);
+        assert_eq!(choker.unchoked(&peers, false), first);
+        assert_eq!(choker.unchoked(&peers, true), first);
+        assert_eq!(choker.unchoked(&peers, true), first);
+
+        let rotated = (0..10).any(|_| {
+            choker.rounds_until_rotation = 0;
+            choker.unchoked(&peers, true) != first
+        });
+        assert!(rotated);
+    }
+
+    #[test]
+    fn test_download_ranking_passes_over_snubbing_peers() {
+        let mut choker = Choker::new(config(1), Ranking::DownloadRate);
+        let rates = |id, download_rate, snubbed| PeerRates {
+            download_rate,
+            snubbed,
+            ..peer(id, 1000 - download_rate)
+        };
+        let peers = [
+            rates(1, 500, true),
+            rates(2, 20, false),
+            rates(3, 10, false),
+        ];
+
+        // Peer 1 is the fastest but snubs us, peer 2 is the fastest of the others.
+        for _ in 0..10 {
+            assert!(choker.unchoked(&peers, true).contains(&2));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_snubbing_us_loses_its_slot() {
+        let peers = Arc::new(ChokedPeers::new(ChokerConfig {
+            regular_slots: 1,
+            snub_timeout: Duration::from_millis(50),
+            ..ChokerConfig::default()
+        }));
+        let slots: Vec<ChokeSlot> = (0..3).map(|_| peers.join()).collect();
+        slots[0].set_interested(true);
+        slots[2].set_interested(true);
+        let choked = |slot: &ChokeSlot| *slot.decisions.borrow();
+
+        // Peer 0 sends the most and gets the regular slot, idle peer 2 the optimistic one.
+        let mut rounds = peers.rounds(Ranking::DownloadRate);
+        peers.round(&mut rounds, true);
+        slots[0].add_downloaded(1_000_000);
+        peers.round(&mut rounds, true);
+        assert!(!choked(&slots[0]) && !choked(&slots[2]));
+
+        // Peer 0 keeps its rate until the next scheduled round, but has gone quiet since,
+        // so the regular slot goes to peer 1, which is still sending.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        slots[1].set_interested(true);
+        slots[1].add_downloaded(1_000);
+        peers.round(&mut rounds, false);
+        assert!(choked(&slots[0]));
+        assert!(!choked(&slots[1]) && !choked(&slots[2]));
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::Digest;
+
+use crate::{
+    torrent::{Torrent, TorrentFile, TorrentInfo},
+    Error,
+};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// Piece count the automatic piece length aims to stay under.
+const TARGET_PIECES: u64 = 1500;
+
+pub(crate) struct CreateOptions {
+    pub(crate) announce: String,
+    pub(crate) announce_list: Vec<Vec<String>>,
+    pub(crate) comment: Option<String>,
+    pub(crate) piece_length: Option<u64>,
+    pub(crate) private: bool,
+}
+
+/// Builds a torrent for a file or a directory. Directories become multi-file torrents with
+/// their files in sorted path order.
+pub(crate) fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Torrent, Error> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::InvalidPath(path.display().to_string()))?
+        .to_owned();
+
+    let is_dir = fs::metadata(path)?.is_dir();
+    let mut paths = Vec::new();
+    if is_dir {
+        collect_files(path, &mut paths)?;
+        paths.sort();
+    } else {
+        paths.push(path.to_path_buf());
+    }
+
+    let mut total_length = 0;
+    let mut files = Vec::new();
+    for file_path in &paths {
+        let length = fs::metadata(file_path)?.len();
+        total_length += length;
+        if is_dir {
+            files.push(TorrentFile {
+                length: length as i64,
+                path: relative_path(path, file_path)?,
+            });
+        }
+    }
+
+    let piece_length = match options.piece_length {
+        Some(piece_length) if piece_length > 0 => piece_length,
+        Some(piece_length) => return Err(Error::InvalidPieceLength(piece_length)),
+        None => auto_piece_length(total_length),
+    };
+    let pieces = hash_pieces(&paths, piece_length as usize)?;
+
+    let info = TorrentInfo {
+        length: (!is_dir).then_some(total_length as i64),
+        name,
+        piece_length: piece_length as i64,
+        pieces,
+        files: is_dir.then_some(files),
+        private: options.private.then_some(1),
+    };
+
+    let mut torrent = Torrent::new(options.announce.clone(), info)?;
+    if !options.announce_list.is_empty() {
+        torrent.announce_list = Some(options.announce_list.clone());
+    }
+    torrent.comment = options.comment.clone();
+    torrent.created_by =
+        Some(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_owned());
+    torrent.creation_date = Some(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64),
+    );
+
+    Ok(torrent)
+}
+
+/// Picks the smallest power of two that keeps the piece count near `TARGET_PIECES`.
+fn auto_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), paths)?;
+        } else if file_type.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn relative_path(root: &Path, file_path: &Path) -> Result<Vec<String>, Error> {
+    file_path
+        .strip_prefix(root)
+        .map_err(|_| Error::InvalidPath(file_path.display().to_string()))?
+        .components()
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .map(str::to_owned)
+                .ok_or_else(|| Error::InvalidPath(file_path.display().to_string()))
+        })
+        .collect()
+}
+
+/// Hashes the concatenation of all files, piece by piece.
+fn hash_pieces(paths: &[PathBuf], piece_length: usize) -> Result<Vec<u8>, Error> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+
+    for path in paths {
+        let mut file = fs::File::open(path)?;
+        loop {
+            let read = (&mut file)
+                .take((piece_length - piece.len()) as u64)
+                .read_to_end(&mut piece)?;
+            if piece.len() == piece_length {
+                pieces.extend_from_slice(&sha1::Sha1::digest(&piece));
+                piece.clear();
+            }
+            if read == 0 {
+                break;
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&sha1::Sha1::digest(&piece));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_multi_file_torrent_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("dataset");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.txt"), vec![b'b'; 20_000]).unwrap();
+        fs::write(root.join("sub").join("a.txt"), vec![b'a'; 30_000]).unwrap();
+
+        let options = CreateOptions {
+            announce: "http://tracker/announce".to_owned(),
+            announce_list: vec![vec!["http://tracker/announce".to_owned()]],
+            comment: None,
+            piece_length: Some(MIN_PIECE_LENGTH),
+            private: true,
+        };
+        let torrent = create_torrent(&root, &options).unwrap();
+        let parsed = Torrent::from_bencode(&torrent.to_bencode().unwrap()).unwrap();
+
+        assert_eq!(parsed.info_hash(), torrent.info_hash());
+        assert_eq!(parsed.info.length(), 50_000);
+        assert_eq!(parsed.num_pieces(), 4);
+        assert_eq!(parsed.info.private, Some(1));
+
+        let mut content = vec![b'b'; 20_000];
+        content.extend(vec![b'a'; 30_000]);
+        let expected: Vec<String> = content
+            .chunks(MIN_PIECE_LENGTH as usize)
+            .map(|piece| hex::encode(sha1::Sha1::digest(piece)))
+            .collect();
+        assert_eq!(parsed.piece_hashes(), expected);
+    }
+}
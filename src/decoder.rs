@@ -1,18 +1,124 @@
-use serde_json::json;
+use std::collections::BTreeMap;
 
-use crate::Error;
+use crate::{bencode::Bencode, stream_decoder::Limits, Error};
+
+/// How the decoder treats input that is valid bencode but not in canonical form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DecodeMode {
+    /// Fail on the first non-canonical construct.
+    Strict,
+    /// Accept the input and record a `Warning` for every non-canonical construct.
+    Lenient,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NonCanonical {
+    IntegerLeadingZero,
+    IntegerPlusSign,
+    NegativeZero,
+    StringLengthLeadingZero,
+    UnsortedKey(Vec<u8>),
+    DuplicateKey(Vec<u8>),
+    TrailingData(usize),
+}
+
+impl std::fmt::Display for NonCanonical {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NonCanonical::IntegerLeadingZero => write!(f, "integer has leading zeros"),
+            NonCanonical::IntegerPlusSign => write!(f, "integer has a plus sign"),
+            NonCanonical::NegativeZero => write!(f, "integer is negative zero"),
+            NonCanonical::StringLengthLeadingZero => {
+                write!(f, "string length has leading zeros")
+            }
+            NonCanonical::UnsortedKey(key) => {
+                write!(
+                    f,
+                    "dict key {:?} is out of order",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            NonCanonical::DuplicateKey(key) => {
+                write!(
+                    f,
+                    "dict key {:?} is duplicated",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            NonCanonical::TrailingData(len) => {
+                write!(f, "{} bytes of trailing data after the value", len)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Warning {
+    pub(crate) offset: usize,
+    pub(crate) reason: NonCanonical,
+}
 
 pub(crate) struct Decoder<'a> {
     input: &'a [u8],
     index: usize,
+    mode: DecodeMode,
+    warnings: Vec<Warning>,
+    limits: Limits,
+    depth: usize,
 }
 
 impl<'a> Decoder<'a> {
     pub(crate) fn new(input: &'a [u8]) -> Self {
-        Self { input, index: 0 }
+        Self::with_mode(input, DecodeMode::Lenient)
+    }
+
+    pub(crate) fn with_mode(input: &'a [u8], mode: DecodeMode) -> Self {
+        Self {
+            input,
+            index: 0,
+            mode,
+            warnings: Vec::new(),
+            limits: Limits::default(),
+            depth: 0,
+        }
+    }
+
+    /// A lenient decoder for untrusted input that must stay within `limits`.
+    pub(crate) fn with_limits(input: &'a [u8], limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Self::new(input)
+        }
     }
 
-    pub(crate) fn decode(&mut self) -> Result<serde_json::Value, Error> {
+    /// Non-canonical constructs accepted so far in lenient mode.
+    pub(crate) fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Decodes a single value that must span the whole input.
+    pub(crate) fn decode(&mut self) -> Result<Bencode, Error> {
+        let value = self.decode_value()?;
+        if self.index < self.input.len() {
+            self.non_canonical(
+                self.index,
+                NonCanonical::TrailingData(self.input.len() - self.index),
+            )?;
+        }
+        Ok(value)
+    }
+
+    fn non_canonical(&mut self, offset: usize, reason: NonCanonical) -> Result<(), Error> {
+        match self.mode {
+            DecodeMode::Strict => Err(Error::NonCanonical(offset, reason)),
+            DecodeMode::Lenient => {
+                self.warnings.push(Warning { offset, reason });
+                Ok(())
+            }
+        }
+    }
+
+    fn decode_value(&mut self) -> Result<Bencode, Error> {
         let encoded_value = &self.input[self.index..];
         match encoded_value.first() {
             Some(digit) if digit.is_ascii_digit() => self.parse_bencode_string(),
@@ -24,7 +130,7 @@ impl<'a> Decoder<'a> {
         }
     }
 
-    fn parse_bencode_string(&mut self) -> Result<serde_json::Value, crate::Error> {
+    fn parse_bencode_string(&mut self) -> Result<Bencode, crate::Error> {
         let encoded_value = &self.input[self.index..];
 
         let colon_index = encoded_value
@@ -34,24 +140,28 @@ impl<'a> Decoder<'a> {
         let number_string =
             std::str::from_utf8(&encoded_value[..colon_index]).map_err(|_| Error::InvalidUTF8)?;
         let number = number_string
-            .parse::<i64>()
+            .parse::<usize>()
             .map_err(|_| Error::NotNumber(number_string.to_string()))?;
 
-        if colon_index + number as usize >= encoded_value.len() {
-            return Err(Error::BencodeStringLengthMismatch);
+        if number_string.len() > 1 && number_string.starts_with('0') {
+            self.non_canonical(self.index, NonCanonical::StringLengthLeadingZero)?;
         }
 
-        let bytes = &encoded_value[colon_index + 1..colon_index + 1 + number as usize];
-        self.index += number as usize + 1 + colon_index;
-
-        //TODO handle non-UTF8 strings better
-        match std::str::from_utf8(bytes) {
-            Ok(s) => Ok(serde_json::Value::String(s.to_string())),
-            _ => Ok(json!(bytes.to_vec())),
+        let end = colon_index
+            .checked_add(number)
+            .filter(|end| *end < encoded_value.len())
+            .ok_or(Error::BencodeStringLengthMismatch)?;
+        if number > self.limits.max_string_length {
+            return Err(Error::StringTooLong(number));
         }
+
+        let bytes = &encoded_value[colon_index + 1..=end];
+        self.index += end + 1;
+
+        Ok(Bencode::Bytes(bytes.to_vec()))
     }
 
-    fn parse_bencode_integer(&mut self) -> Result<serde_json::Value, Error> {
+    fn parse_bencode_integer(&mut self) -> Result<Bencode, Error> {
         let encoded_value = &self.input[self.index + 1..];
 
         let end_index = encoded_value
@@ -64,28 +174,49 @@ impl<'a> Decoder<'a> {
             .parse::<i64>()
             .map_err(|_| Error::NotNumber(number_str.to_string()))?;
 
+        let digits = number_str.trim_start_matches(['-', '+']);
+        if number_str.starts_with('+') {
+            self.non_canonical(self.index, NonCanonical::IntegerPlusSign)?;
+        } else if number_str.starts_with('-') && number == 0 {
+            self.non_canonical(self.index, NonCanonical::NegativeZero)?;
+        } else if digits.len() > 1 && digits.starts_with('0') {
+            self.non_canonical(self.index, NonCanonical::IntegerLeadingZero)?;
+        }
+
         self.index += end_index + 2; // Skip 'i', number, and 'e'
 
-        Ok(serde_json::Value::Number(number.into()))
+        Ok(Bencode::Int(number))
     }
 
-    fn parse_bencode_list(&mut self) -> Result<serde_json::Value, Error> {
+    /// Steps into a list or dict, refusing input nested deeper than the default limit.
+    fn enter(&mut self) -> Result<(), Error> {
+        let max_depth = self.limits.max_depth;
+        self.depth += 1;
+        if self.depth > max_depth {
+            return Err(Error::NestingTooDeep(max_depth));
+        }
+        Ok(())
+    }
+
+    fn parse_bencode_list(&mut self) -> Result<Bencode, Error> {
         // Skip the 'l'
         self.index += 1;
+        self.enter()?;
         let array = self.parse_bencode_list_inner()?;
         // Skip the 'e'
         self.index += 1;
+        self.depth -= 1;
 
-        Ok(serde_json::Value::Array(array))
+        Ok(Bencode::List(array))
     }
 
-    fn parse_bencode_list_inner(&mut self) -> Result<Vec<serde_json::Value>, Error> {
+    fn parse_bencode_list_inner(&mut self) -> Result<Vec<Bencode>, Error> {
         let mut list = Vec::new();
 
         let mut encoded_value = &self.input[self.index..];
 
         while self.index < self.input.len() && encoded_value[0] != b'e' {
-            list.push(self.decode()?);
+            list.push(self.decode_value()?);
             encoded_value = &self.input[self.index..];
         }
 
@@ -96,27 +227,29 @@ impl<'a> Decoder<'a> {
         Ok(list)
     }
 
-    fn parse_bencode_dict(&mut self) -> Result<serde_json::Value, Error> {
+    fn parse_bencode_dict(&mut self) -> Result<Bencode, Error> {
         // Skip the 'd'
         self.index += 1;
+        self.enter()?;
         let dict = self.parse_bencode_dict_inner()?;
         // Skip the 'e'
         self.index += 1;
+        self.depth -= 1;
 
-        Ok(serde_json::Value::Object(dict))
+        Ok(Bencode::Dict(dict))
     }
 
-    fn parse_bencode_dict_inner(
-        &mut self,
-    ) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
-        let mut dict = serde_json::Map::new();
+    fn parse_bencode_dict_inner(&mut self) -> Result<BTreeMap<Vec<u8>, Bencode>, Error> {
+        let mut dict = BTreeMap::new();
+        let mut previous_key: Option<Vec<u8>> = None;
 
         let mut encoded_value = &self.input[self.index..];
         while self.index < self.input.len() && encoded_value[0] != b'e' {
-            let key = self.decode()?;
-            let value = self.decode()?;
+            let key_offset = self.index;
+            let key = self.decode_value()?;
+            let value = self.decode_value()?;
             let key = match key {
-                serde_json::Value::String(s) => s,
+                Bencode::Bytes(bytes) => bytes,
                 _ => return Err(Error::InvalidDictKey(format!("{:?}", key))),
             };
 
@@ -124,10 +257,24 @@ impl<'a> Decoder<'a> {
                 return Err(Error::UnexpectedEOF);
             }
 
+            if dict.contains_key(&key) {
+                self.non_canonical(key_offset, NonCanonical::DuplicateKey(key.clone()))?;
+            } else if previous_key
+                .as_ref()
+                .is_some_and(|previous| key < *previous)
+            {
+                self.non_canonical(key_offset, NonCanonical::UnsortedKey(key.clone()))?;
+            }
+
+            previous_key = Some(key.clone());
             dict.insert(key, value);
             encoded_value = &self.input[self.index..];
         }
 
+        if self.index >= self.input.len() {
+            return Err(Error::UnexpectedEOF);
+        }
+
         Ok(dict)
     }
 }
@@ -144,10 +291,10 @@ mod tests {
         assert!(decoded_value.is_ok());
         assert_eq!(
             decoded_value.unwrap(),
-            serde_json::json!({
-                "foo": "bar",
-                "hello": 52
-            })
+            Bencode::Dict(BTreeMap::from([
+                (b"foo".to_vec(), Bencode::Bytes(b"bar".to_vec())),
+                (b"hello".to_vec(), Bencode::Int(52)),
+            ]))
         );
     }
 
@@ -159,7 +306,11 @@ mod tests {
         assert!(decoded_value.is_ok());
         assert_eq!(
             decoded_value.unwrap(),
-            serde_json::json!(["foo", "bar", 52])
+            Bencode::List(vec![
+                Bencode::Bytes(b"foo".to_vec()),
+                Bencode::Bytes(b"bar".to_vec()),
+                Bencode::Int(52),
+            ])
         );
     }
 
@@ -169,7 +320,7 @@ mod tests {
         let mut bencode_decoder = Decoder::new(input);
         let decoded_value = bencode_decoder.decode();
         assert!(decoded_value.is_ok());
-        assert_eq!(decoded_value.unwrap(), serde_json::json!(-52));
+        assert_eq!(decoded_value.unwrap(), Bencode::Int(-52));
     }
 
     #[test]
@@ -178,7 +329,97 @@ mod tests {
         let mut bencode_decoder = Decoder::new(input);
         let decoded_value = bencode_decoder.decode();
         assert!(decoded_value.is_ok());
-        assert_eq!(decoded_value.unwrap(), serde_json::json!("hello"));
+        assert_eq!(decoded_value.unwrap(), Bencode::Bytes(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_bencode_binary_string_decoder() {
+        let input = b"3:\xff\x00\x80";
+        let mut bencode_decoder = Decoder::new(input);
+        assert_eq!(
+            bencode_decoder.decode().unwrap(),
+            Bencode::Bytes(vec![0xff, 0x00, 0x80])
+        );
+    }
+
+    #[test]
+    fn test_overlong_string_length_is_rejected() {
+        for input in [&b"18446744073709551615:abc"[..], b"4:abc"] {
+            assert!(matches!(
+                Decoder::new(input).decode(),
+                Err(Error::BencodeStringLengthMismatch)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_non_canonical_integers() {
+        for (input, reason) in [
+            (&b"i03e"[..], NonCanonical::IntegerLeadingZero),
+            (b"i-0e", NonCanonical::NegativeZero),
+            (b"i+3e", NonCanonical::IntegerPlusSign),
+        ] {
+            let mut bencode_decoder = Decoder::with_mode(input, DecodeMode::Strict);
+            assert!(matches!(
+                bencode_decoder.decode(),
+                Err(Error::NonCanonical(0, r)) if r == reason
+            ));
+        }
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_bad_dict_keys_and_trailing_data() {
+        let mut bencode_decoder = Decoder::with_mode(b"d1:bi1e1:ai2ee", DecodeMode::Strict);
+        assert!(matches!(
+            bencode_decoder.decode(),
+            Err(Error::NonCanonical(7, NonCanonical::UnsortedKey(key))) if key == b"a"
+        ));
+
+        let mut bencode_decoder = Decoder::with_mode(b"d1:ai1e1:ai2ee", DecodeMode::Strict);
+        assert!(matches!(
+            bencode_decoder.decode(),
+            Err(Error::NonCanonical(7, NonCanonical::DuplicateKey(key))) if key == b"a"
+        ));
+
+        let mut bencode_decoder = Decoder::with_mode(b"i1exyz", DecodeMode::Strict);
+        assert!(matches!(
+            bencode_decoder.decode(),
+            Err(Error::NonCanonical(3, NonCanonical::TrailingData(3)))
+        ));
+    }
+
+    #[test]
+    fn test_lenient_mode_collects_warnings() {
+        let mut bencode_decoder = Decoder::new(b"d1:bi01e1:ai-0ee");
+        assert!(bencode_decoder.decode().is_ok());
+        assert_eq!(
+            bencode_decoder.warnings(),
+            [
+                Warning {
+                    offset: 4,
+                    reason: NonCanonical::IntegerLeadingZero
+                },
+                Warning {
+                    offset: 11,
+                    reason: NonCanonical::NegativeZero
+                },
+                Warning {
+                    offset: 8,
+                    reason: NonCanonical::UnsortedKey(b"a".to_vec())
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deep_nesting_is_rejected() {
+        let mut input = vec![b'l'; 100_000];
+        input.extend(vec![b'e'; 100_000]);
+        assert!(matches!(
+            Decoder::new(&input).decode(),
+            Err(Error::NestingTooDeep(_))
+        ));
+        assert!(Decoder::new(b"llleee").decode().is_ok());
     }
 
     #[test]
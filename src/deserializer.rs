@@ -0,0 +1,605 @@
+use std::{fmt::Write, ops::Range};
+
+use serde::de::{
+    self,
+    value::{BorrowedBytesDeserializer, BorrowedStrDeserializer},
+    DeserializeSeed, IntoDeserializer, Visitor,
+};
+
+use crate::{stream_decoder::Limits, Error};
+
+/// Deserializes `T` from a bencoded buffer with the default limits. Errors carry the byte
+/// offset and the key path (e.g. `info.files[1].length`) of the value that failed.
+pub(crate) fn from_bytes<'de, T>(input: &'de [u8]) -> Result<T, Error>
+where
+    T: de::Deserialize<'de>,
+{
+    let mut deserializer = Deserializer::new(input);
+    deserializer.deserialize()
+}
+
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+pub(crate) struct Deserializer<'de> {
+    input: &'de [u8],
+    index: usize,
+    path: Vec<PathSegment>,
+    limits: Limits,
+    depth: usize,
+    /// Byte span of every value in the top-level dictionary, keyed by its dict key.
+    top_level_spans: Vec<(&'de [u8], Range<usize>)>,
+}
+
+impl<'de> Deserializer<'de> {
+    pub(crate) fn new(input: &'de [u8]) -> Self {
+        Self::with_limits(input, Limits::default())
+    }
+
+    pub(crate) fn with_limits(input: &'de [u8], limits: Limits) -> Self {
+        Self {
+            input,
+            index: 0,
+            path: Vec::new(),
+            limits,
+            depth: 0,
+            top_level_spans: Vec::new(),
+        }
+    }
+
+    pub(crate) fn deserialize<T>(&mut self) -> Result<T, Error>
+    where
+        T: de::Deserialize<'de>,
+    {
+        T::deserialize(&mut *self).map_err(|e| self.locate(e))
+    }
+
+    /// Number of input bytes consumed so far. Lets callers find data appended after a value.
+    pub(crate) fn offset(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the exact encoded bytes of a value in the top-level dictionary, as they
+    /// appeared in the input. Only available after `deserialize` has run.
+    pub(crate) fn raw_value(&self, key: &str) -> Option<&'de [u8]> {
+        self.top_level_spans
+            .iter()
+            .find(|(k, _)| *k == key.as_bytes())
+            .map(|(_, span)| &self.input[span.clone()])
+    }
+
+    fn locate(&self, error: Error) -> Error {
+        if let Error::Bencode { .. } | Error::NestingTooDeep(_) | Error::StringTooLong(_) = error {
+            return error;
+        }
+
+        let mut path = String::new();
+        for segment in &self.path {
+            match segment {
+                PathSegment::Key(key) if path.is_empty() => path.push_str(key),
+                PathSegment::Key(key) => write!(path, ".{}", key).unwrap(),
+                PathSegment::Index(index) => write!(path, "[{}]", index).unwrap(),
+            }
+        }
+
+        Error::Bencode {
+            offset: self.index,
+            path,
+            message: error.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.input
+            .get(self.index)
+            .copied()
+            .ok_or(Error::UnexpectedEOF)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), Error> {
+        match self.peek()? {
+            b if b == byte => {
+                self.index += 1;
+                Ok(())
+            }
+            b => Err(Error::InvalidBencodeType(b)),
+        }
+    }
+
+    /// Steps into a list or dict, refusing input nested deeper than the limit so malicious
+    /// data can't exhaust the stack.
+    fn enter(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > self.limits.max_depth {
+            return Err(Error::NestingTooDeep(self.limits.max_depth));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn parse_integer(&mut self) -> Result<i64, Error> {
+        self.expect(b'i')?;
+        let rest = &self.input[self.index..];
+        let end_index = rest
+            .iter()
+            .position(|&x| x == b'e')
+            .ok_or(Error::MissingTerminator)?;
+        let number_str = std::str::from_utf8(&rest[..end_index]).map_err(|_| Error::InvalidUTF8)?;
+        let number = number_str
+            .parse::<i64>()
+            .map_err(|_| Error::NotNumber(number_str.to_string()))?;
+        self.index += end_index + 1;
+        Ok(number)
+    }
+
+    fn parse_bytes(&mut self) -> Result<&'de [u8], Error> {
+        let rest = &self.input[self.index..];
+        if !rest.first().is_some_and(u8::is_ascii_digit) {
+            return Err(Error::InvalidBencodeType(self.peek()?));
+        }
+
+        let colon_index = rest
+            .iter()
+            .position(|&x| x == b':')
+            .ok_or(Error::BencodeStringNoColon)?;
+        let number_str =
+            std::str::from_utf8(&rest[..colon_index]).map_err(|_| Error::InvalidUTF8)?;
+        let length = number_str
+            .parse::<usize>()
+            .map_err(|_| Error::NotNumber(number_str.to_string()))?;
+        if length > self.limits.max_string_length {
+            return Err(Error::StringTooLong(length));
+        }
+
+        let start = colon_index + 1;
+        if rest.len() - start < length {
+            return Err(Error::BencodeStringLengthMismatch);
+        }
+
+        self.index += start + length;
+        Ok(&rest[start..start + length])
+    }
+
+    fn parse_str(&mut self) -> Result<&'de str, Error> {
+        let bytes = self.parse_bytes()?;
+        std::str::from_utf8(bytes).map_err(|_| Error::InvalidUTF8)
+    }
+
+    fn skip_value(&mut self) -> Result<(), Error> {
+        match self.peek()? {
+            b'i' => self.parse_integer().map(drop),
+            b'l' => {
+                self.index += 1;
+                self.enter()?;
+                while self.peek()? != b'e' {
+                    self.skip_value()?;
+                }
+                self.index += 1;
+                self.leave();
+                Ok(())
+            }
+            b'd' => {
+                self.index += 1;
+                self.enter()?;
+                while self.peek()? != b'e' {
+                    self.parse_bytes()?;
+                    self.skip_value()?;
+                }
+                self.index += 1;
+                self.leave();
+                Ok(())
+            }
+            _ => self.parse_bytes().map(drop),
+        }
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.peek()? {
+            b'i' => visitor.visit_i64(self.parse_integer()?),
+            b'l' => self.deserialize_seq(visitor),
+            b'd' => self.deserialize_map(visitor),
+            _ => {
+                let bytes = self.parse_bytes()?;
+                match std::str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_borrowed_str(s),
+                    Err(_) => visitor.visit_borrowed_bytes(bytes),
+                }
+            }
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_bool(self.parse_integer()? != 0)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.parse_integer()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::Custom(
+            "bencode has no floating point type".to_owned(),
+        ))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::Custom(
+            "bencode has no floating point type".to_owned(),
+        ))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.parse_str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.parse_bytes()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // Bencode has no null: absent dict keys become `None`, anything present is `Some`.
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.skip_value()?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.expect(b'l')?;
+        self.enter()?;
+        let value = visitor.visit_seq(ListAccess {
+            deserializer: &mut *self,
+            index: 0,
+        })?;
+        self.expect(b'e')?;
+        self.leave();
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.expect(b'd')?;
+        self.enter()?;
+        let value = visitor.visit_map(DictAccess {
+            deserializer: &mut *self,
+            key: &[],
+            value_start: 0,
+        })?;
+        self.expect(b'e')?;
+        self.leave();
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.peek()? {
+            // A unit variant is just its name, anything else is `d<variant><value>e`.
+            b'd' => {
+                self.index += 1;
+                self.enter()?;
+                let value = visitor.visit_enum(EnumAccess {
+                    deserializer: &mut *self,
+                })?;
+                self.expect(b'e')?;
+                self.leave();
+                Ok(value)
+            }
+            _ => visitor.visit_enum(self.parse_str()?.into_deserializer()),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.skip_value()?;
+        visitor.visit_unit()
+    }
+}
+
+struct ListAccess<'a, 'de> {
+    deserializer: &'a mut Deserializer<'de>,
+    index: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for ListAccess<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.deserializer.peek()? == b'e' {
+            return Ok(None);
+        }
+
+        self.deserializer.path.push(PathSegment::Index(self.index));
+        let value = seed.deserialize(&mut *self.deserializer)?;
+        self.deserializer.path.pop();
+        self.index += 1;
+        Ok(Some(value))
+    }
+}
+
+struct DictAccess<'a, 'de> {
+    deserializer: &'a mut Deserializer<'de>,
+    key: &'de [u8],
+    value_start: usize,
+}
+
+impl<'de> de::MapAccess<'de> for DictAccess<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if self.deserializer.peek()? == b'e' {
+            return Ok(None);
+        }
+
+        let key = self.deserializer.parse_bytes()?;
+        self.deserializer
+            .path
+            .push(PathSegment::Key(String::from_utf8_lossy(key).into_owned()));
+        self.key = key;
+        self.value_start = self.deserializer.index;
+
+        match std::str::from_utf8(key) {
+            Ok(s) => seed.deserialize(BorrowedStrDeserializer::new(s)),
+            Err(_) => seed.deserialize(BorrowedBytesDeserializer::new(key)),
+        }
+        .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = seed.deserialize(&mut *self.deserializer)?;
+
+        let deserializer = &mut *self.deserializer;
+        if deserializer.path.len() == 1 {
+            deserializer
+                .top_level_spans
+                .push((self.key, self.value_start..deserializer.index));
+        }
+        deserializer.path.pop();
+        Ok(value)
+    }
+}
+
+struct EnumAccess<'a, 'de> {
+    deserializer: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess<'_, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = self.deserializer.parse_str()?;
+        let value = seed.deserialize(BorrowedStrDeserializer::<Error>::new(variant))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for EnumAccess<'_, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        self.deserializer.skip_value()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(&mut *self.deserializer)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(&mut *self.deserializer, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(&mut *self.deserializer, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        private: Option<u8>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct MetaInfo {
+        announce: String,
+        info: Info,
+    }
+
+    #[test]
+    fn test_deserialize_struct() {
+        let input = b"d8:announce3:url4:infod4:name1:a12:piece lengthi16e6:pieces2:\xff\x00ee";
+        let mut deserializer = Deserializer::new(input);
+        let meta_info: MetaInfo = deserializer.deserialize().unwrap();
+        assert_eq!(
+            meta_info,
+            MetaInfo {
+                announce: "url".to_owned(),
+                info: Info {
+                    name: "a".to_owned(),
+                    piece_length: 16,
+                    pieces: vec![0xff, 0x00],
+                    private: None,
+                },
+            }
+        );
+        assert_eq!(
+            deserializer.raw_value("info"),
+            Some(&b"d4:name1:a12:piece lengthi16e6:pieces2:\xff\x00e"[..])
+        );
+    }
+
+    #[test]
+    fn test_deserialize_error_location() {
+        let input = b"d8:announce3:url4:infod4:name1:a12:piece length3:bad6:pieces0:ee";
+        let error = from_bytes::<MetaInfo>(input).unwrap_err();
+        match error {
+            Error::Bencode { offset, path, .. } => {
+                assert_eq!(offset, 47);
+                assert_eq!(path, "info.piece length");
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_list_index_in_path() {
+        let error = from_bytes::<Vec<u32>>(b"li1ei-1ee").unwrap_err();
+        assert!(matches!(error, Error::Bencode { path, .. } if path == "[1]"));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_deep_nesting() {
+        let depth = 200_000;
+        let mut input = b"d7:unknown".to_vec();
+        input.extend(vec![b'l'; depth]);
+        input.extend(vec![b'e'; depth]);
+        input.extend(b"4:name1:ae");
+
+        let error = from_bytes::<MetaInfo>(&input).unwrap_err();
+        assert!(matches!(error, Error::NestingTooDeep(64)));
+
+        let mut nested = vec![b'l'; depth];
+        nested.extend(vec![b'e'; depth]);
+        let error = from_bytes::<serde_json::Value>(&nested).unwrap_err();
+        assert!(matches!(error, Error::NestingTooDeep(64)));
+
+        let limits = Limits {
+            max_depth: 2,
+            max_string_length: 3,
+        };
+        let mut deserializer = Deserializer::with_limits(b"ll3:abcee", limits);
+        assert!(deserializer.deserialize::<Vec<Vec<String>>>().is_ok());
+        let mut deserializer = Deserializer::with_limits(b"lll3:abceee", limits);
+        assert!(deserializer.deserialize::<Vec<Vec<Vec<String>>>>().is_err());
+        let mut deserializer = Deserializer::with_limits(b"4:abcd", limits);
+        assert!(matches!(
+            deserializer.deserialize::<String>(),
+            Err(Error::StringTooLong(4))
+        ));
+    }
+}
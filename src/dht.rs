@@ -0,0 +1,889 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinSet};
+
+use crate::{
+    bencode::Bencode, decoder::Decoder, deserializer, encoder::Encoder, random::random_u64,
+    serializer, stream_decoder::Limits, Error,
+};
+
+/// Nodes per routing table bucket, and the number of closest nodes a lookup converges on.
+const K: usize = 8;
+/// Queries a lookup keeps in flight at once.
+const ALPHA: usize = 3;
+/// Failed queries after which a node may be replaced by a newly seen one.
+const MAX_FAILURES: u32 = 2;
+/// How often the token secret changes. Tokens made with the previous secret are still
+/// accepted, so a token stays valid for 5 to 10 minutes.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten after this long without announcing again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// How often a seeding peer announces itself again, well within `PEER_TTL`.
+pub(crate) const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Peers returned for a `get_peers` query, few enough to fit in one datagram.
+const MAX_VALUES: usize = 50;
+/// Info hashes we store announced peers for, and peers stored per info hash. Announces
+/// beyond these are ignored until stored peers expire.
+const MAX_STORED_INFO_HASHES: usize = 1000;
+const MAX_STORED_PEERS: usize = 200;
+const MAX_PACKET_SIZE: usize = 2048;
+/// KRPC messages nest at most a few levels (response, `values`, peer).
+const KRPC_LIMITS: Limits = Limits {
+    max_depth: 8,
+    max_string_length: MAX_PACKET_SIZE,
+};
+
+/// KRPC error codes from BEP 5.
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// Well-known nodes to join the mainline DHT through.
+pub(crate) const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Node ids and info hashes share the same 160-bit space.
+pub(crate) type NodeId = [u8; 20];
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Node {
+    pub(crate) id: NodeId,
+    pub(crate) addr: SocketAddrV4,
+}
+
+struct Entry {
+    node: Node,
+    /// Queries in a row the node didn't answer.
+    failures: u32,
+}
+
+/// Kademlia routing table. Bucket `i` holds up to `K` nodes whose id shares exactly `i`
+/// leading bits with ours, so we know many nodes close to us and a few far away.
+pub(crate) struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub(crate) fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        let distance = distance(&self.own_id, id);
+        distance
+            .iter()
+            .position(|&byte| byte != 0)
+            .map_or(159, |i| i * 8 + distance[i].leading_zeros() as usize)
+    }
+
+    /// Records a node that answered or queried us. A full bucket only makes room by
+    /// dropping a node that stopped answering: long-lived nodes are the most reliable.
+    pub(crate) fn insert(&mut self, node: Node) {
+        if node.id == self.own_id {
+            return;
+        }
+        let bucket_index = self.bucket_index(&node.id);
+        let bucket = &mut self.buckets[bucket_index];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.failures = 0;
+            return;
+        }
+
+        let entry = Entry { node, failures: 0 };
+        if bucket.len() < K {
+            bucket.push(entry);
+        } else if let Some(bad) = bucket
+            .iter_mut()
+            .find(|entry| entry.failures >= MAX_FAILURES)
+        {
+            *bad = entry;
+        }
+    }
+
+    /// Counts a query the node at `addr` didn't answer.
+    pub(crate) fn mark_failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// The `count` responsive nodes closest to `target`, closest first.
+    pub(crate) fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.nodes().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.nodes().count()
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = Node> + '_ {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node)
+    }
+}
+
+/// DHT settings.
+#[derive(Debug, Clone)]
+pub(crate) struct DhtConfig {
+    /// Nodes as `host:port` to join through when the routing table is empty.
+    pub(crate) bootstrap: Vec<String>,
+    /// Where the node id and routing table are kept between runs.
+    pub(crate) state_path: Option<PathBuf>,
+    /// How long a node gets to answer a query.
+    pub(crate) query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bootstrap: DEFAULT_BOOTSTRAP_NODES.map(str::to_owned).to_vec(),
+            state_path: None,
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The node id and routing table saved between runs, nodes in compact form.
+#[derive(Deserialize, Serialize)]
+struct DhtState {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+}
+
+struct TokenSecrets {
+    current: u64,
+    previous: u64,
+    rotated: Instant,
+}
+
+/// Delivers the response to a query, or the error the node answered with.
+type ResponseSender = oneshot::Sender<Result<Bencode, Error>>;
+
+/// What an iterative lookup found.
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    /// The `K` closest nodes that answered, closest first, with the token each handed out.
+    closest: Vec<(Node, Option<Vec<u8>>)>,
+}
+
+/// A mainline DHT node (BEP 5): answers KRPC queries from other nodes and looks up peers.
+pub(crate) struct Dht {
+    id: NodeId,
+    socket: UdpSocket,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    /// Queries awaiting a response, by transaction id, with the node they were sent to.
+    pending: Mutex<HashMap<u16, (SocketAddrV4, ResponseSender)>>,
+    next_transaction: AtomicU16,
+    /// Peers announced to us, per info hash, with the time of their last announce.
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>>,
+    token_secrets: Mutex<TokenSecrets>,
+}
+
+impl Dht {
+    /// Binds the node to a UDP port and starts answering queries, until the runtime shuts
+    /// down. The node id and routing table come from the state file if there is one.
+    pub(crate) async fn bind(port: u16, config: DhtConfig) -> Result<Arc<Self>, Error> {
+        let state = config.state_path.as_ref().and_then(|path| {
+            let bytes = fs::read(path).ok()?;
+            deserializer::from_bytes::<DhtState>(&bytes).ok()
+        });
+        let id = state
+            .as_ref()
+            .and_then(|state| state.id.as_slice().try_into().ok())
+            .unwrap_or_else(random_id);
+
+        let mut table = RoutingTable::new(id);
+        for node in state.iter().flat_map(|state| decode_nodes(&state.nodes)) {
+            table.insert(node);
+        }
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        let dht = Arc::new(Self {
+            id,
+            socket,
+            config,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(random_u64() as u16),
+            peers: Mutex::new(HashMap::new()),
+            token_secrets: Mutex::new(TokenSecrets {
+                current: random_u64(),
+                previous: random_u64(),
+                rotated: Instant::now(),
+            }),
+        });
+        tokio::spawn(Arc::clone(&dht).receive());
+        tokio::spawn(Arc::clone(&dht).expire_peers_periodically());
+        Ok(dht)
+    }
+
+    pub(crate) fn num_nodes(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Joins the network by looking up our own id, which fills the routing table with
+    /// the nodes around us. Starts from the saved nodes, and from the bootstrap nodes if
+    /// none of those answers.
+    pub(crate) async fn bootstrap(self: &Arc<Self>) -> Result<(), Error> {
+        if self.num_nodes() > 0 {
+            let lookup = self.lookup(self.id, "find_node", Vec::new()).await;
+            if !lookup.closest.is_empty() {
+                return Ok(());
+            }
+        }
+
+        let mut seeds = Vec::new();
+        for host in &self.config.bootstrap {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(addrs) => seeds.extend(addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => eprintln!("DHT bootstrap node {} failed: {}", host, e),
+            }
+        }
+        let lookup = self.lookup(self.id, "find_node", seeds).await;
+        if lookup.closest.is_empty() {
+            return Err(Error::DhtBootstrapFailed);
+        }
+        Ok(())
+    }
+
+    /// Finds peers for a torrent.
+    pub(crate) async fn get_peers(self: &Arc<Self>, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash, "get_peers", Vec::new()).await.peers
+    }
+
+    /// Finds peers for a torrent and tells the nodes closest to it that we accept
+    /// connections for it on `port`.
+    pub(crate) async fn announce(
+        self: &Arc<Self>,
+        info_hash: [u8; 20],
+        port: u16,
+    ) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, "get_peers", Vec::new()).await;
+
+        let mut announces = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let args = BTreeMap::from([
+                (b"implied_port".to_vec(), Bencode::Int(0)),
+                (b"info_hash".to_vec(), Bencode::Bytes(info_hash.to_vec())),
+                (b"port".to_vec(), Bencode::Int(port.into())),
+                (b"token".to_vec(), Bencode::Bytes(token)),
+            ]);
+            let dht = Arc::clone(self);
+            announces.spawn(async move { dht.query(node.addr, "announce_peer", args).await });
+        }
+        while announces.join_next().await.is_some() {}
+
+        lookup.peers
+    }
+
+    /// Writes the node id and routing table to the state file, if there is one, replacing
+    /// the previous one atomically.
+    pub(crate) fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+        let nodes: Vec<Node> = self.table.lock().unwrap().nodes().collect();
+        let state = DhtState {
+            id: self.id.to_vec(),
+            nodes: encode_nodes(&nodes),
+        };
+
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, serializer::to_bytes(&state)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Iterative Kademlia lookup: keeps asking the closest nodes not asked yet for nodes
+    /// even closer to `target`, until the `K` closest known have all been asked. `seeds`
+    /// are asked first, for bootstrapping when their ids aren't known.
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        method: &'static str,
+        mut seeds: Vec<SocketAddrV4>,
+    ) -> Lookup {
+        let target_key = if method == "find_node" {
+            "target"
+        } else {
+            "info_hash"
+        };
+        let mut candidates: BTreeMap<NodeId, Node> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut asked = HashSet::new();
+        let mut closest = BTreeMap::new();
+        let mut peers = Vec::new();
+        let mut queries = JoinSet::new();
+
+        loop {
+            while queries.len() < ALPHA {
+                let next = seeds.pop().or_else(|| {
+                    candidates
+                        .values()
+                        .take(K)
+                        .map(|node| node.addr)
+                        .find(|addr| !asked.contains(addr))
+                });
+                let Some(addr) = next else {
+                    break;
+                };
+                asked.insert(addr);
+
+                let args = BTreeMap::from([(
+                    target_key.as_bytes().to_vec(),
+                    Bencode::Bytes(target.to_vec()),
+                )]);
+                let dht = Arc::clone(self);
+                queries.spawn(async move { (addr, dht.query(addr, method, args).await) });
+            }
+
+            let Some(joined) = queries.join_next().await else {
+                break;
+            };
+            let Ok((addr, result)) = joined else {
+                continue;
+            };
+            let response = match result {
+                Ok(response) => response,
+                Err(_) => {
+                    candidates.retain(|_, node| node.addr != addr);
+                    continue;
+                }
+            };
+
+            if let Some(id) = node_id(response.get("id")) {
+                let token = response
+                    .get("token")
+                    .and_then(Bencode::as_bytes)
+                    .map(<[u8]>::to_vec);
+                closest.insert(distance(&id, &target), (Node { id, addr }, token));
+            }
+            let nodes = response
+                .get("nodes")
+                .and_then(Bencode::as_bytes)
+                .unwrap_or_default();
+            for node in decode_nodes(nodes) {
+                if node.id != self.id && !asked.contains(&node.addr) {
+                    candidates.insert(distance(&node.id, &target), node);
+                }
+            }
+            if let Some(Bencode::List(values)) = response.get("values") {
+                let found = values
+                    .iter()
+                    .filter_map(Bencode::as_bytes)
+                    .filter_map(decode_peer)
+                    .map(SocketAddr::V4);
+                for peer in found {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+        }
+
+        Lookup {
+            peers,
+            closest: closest.into_values().take(K).collect(),
+        }
+    }
+
+    /// Sends a query and waits for its response, returning the `r` dictionary.
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: BTreeMap<Vec<u8>, Bencode>,
+    ) -> Result<Bencode, Error> {
+        args.insert(b"id".to_vec(), Bencode::Bytes(self.id.to_vec()));
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let message = Bencode::dict([
+            ("a", Bencode::Dict(args)),
+            ("q", Bencode::Bytes(method.as_bytes().to_vec())),
+            ("t", Bencode::Bytes(transaction.to_be_bytes().to_vec())),
+            ("y", Bencode::Bytes(b"q".to_vec())),
+        ]);
+
+        let message = Encoder::encode(&message)?;
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction, (addr, sender));
+        if let Err(e) = self.socket.send_to(&message, addr).await {
+            self.pending.lock().unwrap().remove(&transaction);
+            return Err(e.into());
+        }
+        match tokio::time::timeout(self.config.query_timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                self.pending.lock().unwrap().remove(&transaction);
+                self.table.lock().unwrap().mark_failed(addr);
+                Err(Error::DhtTimeout)
+            }
+        }
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let Ok((length, SocketAddr::V4(from))) = self.socket.recv_from(&mut buffer).await
+            else {
+                continue;
+            };
+            // Anything that isn't a KRPC message is ignored.
+            let Ok(message) = Decoder::with_limits(&buffer[..length], KRPC_LIMITS).decode() else {
+                continue;
+            };
+            let transaction = message
+                .get("t")
+                .and_then(Bencode::as_bytes)
+                .unwrap_or_default();
+
+            match message.get("y").and_then(Bencode::as_bytes) {
+                Some(b"q") => {
+                    let mut response = match self.answer(&message, from) {
+                        Ok(response) => {
+                            Bencode::dict([("r", response), ("y", Bencode::Bytes(b"r".to_vec()))])
+                        }
+                        Err((code, reason)) => Bencode::dict([
+                            (
+                                "e",
+                                Bencode::List(vec![
+                                    Bencode::Int(code),
+                                    Bencode::Bytes(reason.as_bytes().to_vec()),
+                                ]),
+                            ),
+                            ("y", Bencode::Bytes(b"e".to_vec())),
+                        ]),
+                    };
+                    if let Bencode::Dict(dict) = &mut response {
+                        dict.insert(b"t".to_vec(), Bencode::Bytes(transaction.to_vec()));
+                    }
+                    if let Ok(response) = Encoder::encode(&response) {
+                        let _ = self.socket.send_to(&response, from).await;
+                    }
+                }
+                Some(y @ (b"r" | b"e")) => {
+                    let Ok(transaction) = <[u8; 2]>::try_from(transaction) else {
+                        continue;
+                    };
+                    let transaction = u16::from_be_bytes(transaction);
+                    let mut pending = self.pending.lock().unwrap();
+                    // Only the node the query went to may answer it.
+                    if !matches!(pending.get(&transaction), Some((addr, _)) if *addr == from) {
+                        continue;
+                    }
+                    let (_, sender) = pending.remove(&transaction).expect("checked above");
+                    drop(pending);
+
+                    let result = if y == b"r" {
+                        self.response(&message, from)
+                    } else {
+                        Err(krpc_error(&message))
+                    };
+                    let _ = sender.send(result);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn response(&self, message: &Bencode, from: SocketAddrV4) -> Result<Bencode, Error> {
+        let response = message
+            .get("r")
+            .ok_or_else(|| Error::InvalidDhtMessage("response without r".to_owned()))?;
+        let id = node_id(response.get("id"))
+            .ok_or_else(|| Error::InvalidDhtMessage("response without id".to_owned()))?;
+        self.table.lock().unwrap().insert(Node { id, addr: from });
+        Ok(response.clone())
+    }
+
+    /// Answers a query, or fails with a KRPC error code and message.
+    fn answer(&self, query: &Bencode, from: SocketAddrV4) -> Result<Bencode, (i64, &'static str)> {
+        let args = query
+            .get("a")
+            .ok_or((ERROR_PROTOCOL, "Missing arguments"))?;
+        let id = node_id(args.get("id")).ok_or((ERROR_PROTOCOL, "Invalid id"))?;
+        self.table.lock().unwrap().insert(Node { id, addr: from });
+
+        let mut response = BTreeMap::from([(b"id".to_vec(), Bencode::Bytes(self.id.to_vec()))]);
+        match query.get("q").and_then(Bencode::as_bytes) {
+            Some(b"ping") => {}
+            Some(b"find_node") => {
+                let target =
+                    node_id(args.get("target")).ok_or((ERROR_PROTOCOL, "Invalid target"))?;
+                let nodes = self.table.lock().unwrap().closest(&target, K);
+                response.insert(b"nodes".to_vec(), Bencode::Bytes(encode_nodes(&nodes)));
+            }
+            Some(b"get_peers") => {
+                let info_hash =
+                    node_id(args.get("info_hash")).ok_or((ERROR_PROTOCOL, "Invalid info_hash"))?;
+                let token = token(self.token_secrets().0, from.ip());
+                response.insert(b"token".to_vec(), Bencode::Bytes(token));
+
+                let peers = self.stored_peers(&info_hash);
+                if peers.is_empty() {
+                    let nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                    response.insert(b"nodes".to_vec(), Bencode::Bytes(encode_nodes(&nodes)));
+                } else {
+                    let values = peers
+                        .iter()
+                        .map(|peer| Bencode::Bytes(encode_peer(peer)))
+                        .collect();
+                    response.insert(b"values".to_vec(), Bencode::List(values));
+                }
+            }
+            Some(b"announce_peer") => {
+                let info_hash =
+                    node_id(args.get("info_hash")).ok_or((ERROR_PROTOCOL, "Invalid info_hash"))?;
+                let (current, previous) = self.token_secrets();
+                let valid = args
+                    .get("token")
+                    .and_then(Bencode::as_bytes)
+                    .is_some_and(|given| {
+                        given == token(current, from.ip()) || given == token(previous, from.ip())
+                    });
+                if !valid {
+                    return Err((ERROR_PROTOCOL, "Bad token"));
+                }
+                // With `implied_port` the peer is behind a NAT and listens where it sent from.
+                let port = if args.get("implied_port").and_then(Bencode::as_int) == Some(1) {
+                    from.port()
+                } else {
+                    args.get("port")
+                        .and_then(Bencode::as_int)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .ok_or((ERROR_PROTOCOL, "Invalid port"))?
+                };
+                self.store_peer(info_hash, SocketAddrV4::new(*from.ip(), port));
+            }
+            _ => return Err((ERROR_METHOD_UNKNOWN, "Method Unknown")),
+        }
+        Ok(Bencode::Dict(response))
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], peer: SocketAddrV4) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_STORED_INFO_HASHES {
+            return;
+        }
+        let swarm = peers.entry(info_hash).or_default();
+        if swarm.contains_key(&peer) || swarm.len() < MAX_STORED_PEERS {
+            swarm.insert(peer, Instant::now());
+        }
+    }
+
+    fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(swarm) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        swarm.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        swarm.keys().take(MAX_VALUES).copied().collect()
+    }
+
+    /// Forgets expired peers, and info hashes left without peers, whether or not anyone
+    /// looks them up.
+    fn expire_peers(&self) {
+        self.peers.lock().unwrap().retain(|_, swarm| {
+            swarm.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            !swarm.is_empty()
+        });
+    }
+
+    async fn expire_peers_periodically(self: Arc<Self>) {
+        let mut rounds = tokio::time::interval(PEER_TTL / 10);
+        loop {
+            rounds.tick().await;
+            self.expire_peers();
+        }
+    }
+
+    /// The current and previous token secrets, rotating them when it is time.
+    fn token_secrets(&self) -> (u64, u64) {
+        let mut secrets = self.token_secrets.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = random_u64();
+            secrets.rotated = Instant::now();
+        }
+        (secrets.current, secrets.previous)
+    }
+}
+
+/// A token proves a node asked us for peers from its address before announcing. It is a
+/// hash of the address and a secret, so nothing needs to be remembered per node.
+fn token(secret: u64, ip: &Ipv4Addr) -> Vec<u8> {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(secret.to_be_bytes());
+    hasher.update(ip.octets());
+    hasher.finalize()[..8].to_vec()
+}
+
+fn random_id() -> NodeId {
+    let mut id = [0u8; 20];
+    for chunk in id.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_be_bytes()[..chunk.len()]);
+    }
+    id
+}
+
+fn node_id(value: Option<&Bencode>) -> Option<NodeId> {
+    value?.as_bytes()?.try_into().ok()
+}
+
+fn krpc_error(message: &Bencode) -> Error {
+    match message.get("e") {
+        Some(Bencode::List(error)) => Error::DhtRejected(
+            error.first().and_then(Bencode::as_int).unwrap_or_default(),
+            String::from_utf8_lossy(error.get(1).and_then(Bencode::as_bytes).unwrap_or_default())
+                .into_owned(),
+        ),
+        _ => Error::InvalidDhtMessage("error without e".to_owned()),
+    }
+}
+
+/// Nodes in compact form: 20 bytes of id, 4 of IPv4 address and 2 of port each.
+fn encode_nodes(nodes: &[Node]) -> Vec<u8> {
+    nodes
+        .iter()
+        .flat_map(|node| [&node.id[..], &encode_peer(&node.addr)].concat())
+        .collect()
+}
+
+fn decode_nodes(nodes: &[u8]) -> Vec<Node> {
+    nodes
+        .chunks_exact(26)
+        .filter_map(|chunk| {
+            Some(Node {
+                id: chunk[..20].try_into().ok()?,
+                addr: decode_peer(&chunk[20..])?,
+            })
+        })
+        .collect()
+}
+
+fn encode_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    [&addr.ip().octets()[..], &addr.port().to_be_bytes()].concat()
+}
+
+fn decode_peer(peer: &[u8]) -> Option<SocketAddrV4> {
+    let peer: [u8; 6] = peer.try_into().ok()?;
+    let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+    Some(SocketAddrV4::new(
+        ip,
+        u16::from_be_bytes([peer[4], peer[5]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first_byte: u8, port: u16) -> Node {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        Node {
+            id,
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+        }
+    }
+
+    fn config(bootstrap: Vec<String>) -> DhtConfig {
+        DhtConfig {
+            bootstrap,
+            state_path: None,
+            query_timeout: Duration::from_millis(500),
+        }
+    }
+
+    fn addr(dht: &Dht) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, dht.socket.local_addr().unwrap().port())
+    }
+
+    /// Local nodes that all joined through the first one.
+    async fn spawn_nodes(count: usize) -> Vec<Arc<Dht>> {
+        let first = Dht::bind(0, config(Vec::new())).await.unwrap();
+        let bootstrap = addr(&first).to_string();
+        let mut nodes = vec![first];
+        for _ in 1..count {
+            let dht = Dht::bind(0, config(vec![bootstrap.clone()])).await.unwrap();
+            dht.bootstrap().await.unwrap();
+            nodes.push(dht);
+        }
+        nodes
+    }
+
+    #[test]
+    fn test_routing_table_buckets() {
+        let mut table = RoutingTable::new([0; 20]);
+        // Ids with the top bit set share no leading bit with ours.
+        for i in 0..10 {
+            table.insert(node(0x80 | i, 1000 + i as u16));
+        }
+        assert_eq!(table.buckets[0].len(), K);
+        table.insert(node(0x01, 2000));
+        assert_eq!(table.bucket_index(&node(0x01, 0).id), 7);
+        assert_eq!(table.closest(&[0; 20], 1), [node(0x01, 2000)]);
+
+        // A full bucket only makes room for a node by dropping one that stopped answering.
+        table.insert(node(0x8a, 1010));
+        assert!(!table.closest(&[0; 20], 20).contains(&node(0x8a, 1010)));
+        table.mark_failed(node(0x80, 1000).addr);
+        table.mark_failed(node(0x80, 1000).addr);
+        table.insert(node(0x8a, 1010));
+        assert_eq!(table.closest(&node(0x8a, 0).id, 1), [node(0x8a, 1010)]);
+        assert_eq!(table.len(), K + 1);
+
+        let nodes = table.closest(&[0; 20], 3);
+        assert_eq!(decode_nodes(&encode_nodes(&nodes)), nodes);
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let nodes = spawn_nodes(6).await;
+        let info_hash = [0x5a; 20];
+        assert!(nodes[1].get_peers(info_hash).await.is_empty());
+
+        nodes[2].announce(info_hash, 7000).await;
+        let peers = nodes[5].get_peers(info_hash).await;
+        assert_eq!(peers, ["127.0.0.1:7000".parse().unwrap()]);
+
+        // Announcing takes a token handed out by `get_peers`.
+        let args = BTreeMap::from([
+            (b"info_hash".to_vec(), Bencode::Bytes(info_hash.to_vec())),
+            (b"port".to_vec(), Bencode::Int(7001)),
+            (b"token".to_vec(), Bencode::Bytes(b"forged".to_vec())),
+        ]);
+        assert!(matches!(
+            nodes[1].query(addr(&nodes[0]), "announce_peer", args).await,
+            Err(Error::DhtRejected(ERROR_PROTOCOL, _))
+        ));
+        assert!(matches!(
+            nodes[1]
+                .query(addr(&nodes[0]), "vote", BTreeMap::new())
+                .await,
+            Err(Error::DhtRejected(ERROR_METHOD_UNKNOWN, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_datagrams_are_ignored() {
+        let nodes = spawn_nodes(2).await;
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        for datagram in [
+            &b"18446744073709551615:abc"[..],
+            &b"l".repeat(MAX_PACKET_SIZE),
+            b"d1:y1:qe",
+        ] {
+            socket.send_to(datagram, addr(&nodes[0])).await.unwrap();
+        }
+        nodes[1]
+            .query(addr(&nodes[0]), "ping", BTreeMap::new())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stored_peers_are_bounded() {
+        let dht = Dht::bind(0, config(Vec::new())).await.unwrap();
+        let peer = |port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+
+        for port in 0..=MAX_STORED_PEERS as u16 {
+            dht.store_peer([1; 20], peer(port));
+        }
+        assert_eq!(dht.peers.lock().unwrap()[&[1; 20]].len(), MAX_STORED_PEERS);
+        for i in 0..=MAX_STORED_INFO_HASHES as u32 {
+            let mut info_hash = [2; 20];
+            info_hash[..4].copy_from_slice(&i.to_be_bytes());
+            dht.store_peer(info_hash, peer(1));
+        }
+        assert_eq!(dht.peers.lock().unwrap().len(), MAX_STORED_INFO_HASHES);
+
+        let Some(expired) = Instant::now().checked_sub(PEER_TTL) else {
+            return;
+        };
+        for swarm in dht.peers.lock().unwrap().values_mut() {
+            swarm
+                .values_mut()
+                .for_each(|announced| *announced = expired);
+        }
+        dht.expire_peers();
+        assert!(dht.peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_routing_table_survives_restart() {
+        let nodes = spawn_nodes(3).await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = DhtConfig {
+            state_path: Some(dir.path().join("dht.state")),
+            ..config(vec![addr(&nodes[0]).to_string()])
+        };
+        let dht = Dht::bind(0, config.clone()).await.unwrap();
+        dht.bootstrap().await.unwrap();
+        dht.save().unwrap();
+
+        // Without bootstrap nodes, joining only works from the saved nodes.
+        let restored = Dht::bind(
+            0,
+            DhtConfig {
+                bootstrap: Vec::new(),
+                ..config
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(restored.id, dht.id);
+        assert_eq!(restored.num_nodes(), 3);
+        restored.bootstrap().await.unwrap();
+    }
+}
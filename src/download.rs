@@ -0,0 +1,778 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sha1::Digest;
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinSet,
+};
+
+use crate::{
+    choke::{ChokedPeers, ChokerConfig, Ranking},
+    peer::{PeerConnection, PeerMessage, PeerMessageType, DEFAULT_PIPELINE_DEPTH},
+    picker::PiecePicker,
+    resume,
+    seed::SharedTorrent,
+    storage::Preallocation,
+    torrent::Torrent,
+    Error,
+};
+
+/// Peers that send this many pieces failing the hash check are disconnected for good.
+const MAX_CORRUPT_PIECES: usize = 3;
+/// Least time between two saves of the resume state while pieces come in. The state is
+/// saved again whenever the download ends.
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tuning knobs for a download.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DownloadOptions {
+    /// Maximum number of peers downloading at the same time.
+    pub(crate) max_peers: usize,
+    /// Outstanding block requests per peer.
+    pub(crate) pipeline_depth: usize,
+    pub(crate) preallocation: Preallocation,
+    /// Choking of the peers we upload to while downloading.
+    pub(crate) choker_config: ChokerConfig,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            max_peers: 30,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            preallocation: Preallocation::default(),
+            choker_config: ChokerConfig::default(),
+        }
+    }
+}
+
+/// What went wrong along the way of a download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DownloadStats {
+    /// Pieces discarded because they didn't match their hash.
+    pub(crate) hash_failures: usize,
+    /// Peers disconnected for sending too many corrupt pieces.
+    pub(crate) banned_peers: usize,
+}
+
+/// Live counters of a download, for announcing to trackers while it runs.
+pub(crate) struct Progress {
+    /// Bytes of verified piece data received.
+    pub(crate) downloaded: AtomicU64,
+    /// Bytes still missing.
+    pub(crate) left: AtomicU64,
+}
+
+impl Progress {
+    pub(crate) fn new(torrent: &Torrent) -> Self {
+        Self {
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(torrent.info.length() as u64),
+        }
+    }
+}
+
+/// The piece picker shared between all peer workers.
+struct SharedPicker {
+    picker: Mutex<PiecePicker>,
+    /// Woken whenever a piece completes or a download is given up.
+    changed: Notify,
+}
+
+impl SharedPicker {
+    /// Takes the next piece for the peer among those `allowed`. Waits while the only pieces
+    /// it could help with are in flight on other peers, since they may still fail and come
+    /// back. Returns `None` once there is nothing left this peer can help with.
+    async fn take(&self, allowed: impl Fn(usize) -> bool) -> Option<usize> {
+        loop {
+            // Register for wakeups before looking at the state so none are missed.
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            {
+                let mut picker = self.picker.lock().unwrap();
+                if let Some(piece_index) = picker.pick(&allowed) {
+                    return Some(piece_index);
+                }
+                if !picker.can_use(&allowed) {
+                    return None;
+                }
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Resolves once the piece has been completed, by any peer.
+    async fn completed(&self, piece_index: usize) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if self.picker.lock().unwrap().is_piece_complete(piece_index) {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    fn complete(&self, piece_index: usize) -> bool {
+        let completed = self.picker.lock().unwrap().complete(piece_index);
+        self.changed.notify_waiters();
+        completed
+    }
+
+    fn abort(&self, piece_index: usize) {
+        self.picker.lock().unwrap().abort(piece_index);
+        self.changed.notify_waiters();
+    }
+
+    /// Counts the pieces the peer announced since the last call into the availability.
+    fn update_availability(&self, peer: &PeerConnection, known: &mut [bool]) {
+        let mut picker = self.picker.lock().unwrap();
+        for (piece_index, known) in known.iter_mut().enumerate() {
+            if !*known && peer.has_piece(piece_index) {
+                *known = true;
+                picker.add_availability(piece_index);
+            }
+        }
+    }
+
+    fn forget_availability(&self, known: &[bool]) {
+        let mut picker = self.picker.lock().unwrap();
+        for piece_index in (0..known.len()).filter(|&i| known[i]) {
+            picker.remove_availability(piece_index);
+        }
+    }
+}
+
+/// What the peer workers of a download share.
+struct Workers {
+    shared: Arc<SharedTorrent>,
+    picker: SharedPicker,
+    /// Decides which peers we upload to, by how fast they send us data.
+    choked: Arc<ChokedPeers>,
+    stats: Mutex<DownloadStats>,
+    peer_id: [u8; 20],
+    options: DownloadOptions,
+}
+
+/// Checks a downloaded piece against its hash from the torrent.
+pub(crate) fn verify_piece(
+    torrent: &Torrent,
+    piece_index: usize,
+    piece: &[u8],
+) -> Result<(), Error> {
+    if torrent.piece_hash(piece_index) != sha1::Sha1::digest(piece).as_slice() {
+        return Err(Error::PieceHashMismatch(piece_index));
+    }
+    Ok(())
+}
+
+/// A download with its local data opened, ready to fetch what is missing.
+pub(crate) struct Download {
+    output: PathBuf,
+    options: DownloadOptions,
+    /// The local data, served to the peers we download from as it comes in.
+    shared: Arc<SharedTorrent>,
+    have: Vec<bool>,
+}
+
+impl Download {
+    /// Opens the output, continuing an earlier, interrupted download of the same torrent,
+    /// and sets `progress.left` to what is still missing.
+    pub(crate) fn open(
+        torrent: &Torrent,
+        output: &Path,
+        options: DownloadOptions,
+        progress: &Progress,
+    ) -> Result<Self, Error> {
+        let (storage, have) = resume::open(torrent, output, options.preallocation)?;
+        let num_pieces = torrent.num_pieces();
+        let left = (0..num_pieces)
+            .filter(|&i| !have[i])
+            .map(|i| torrent.piece_size(i) as u64)
+            .sum();
+        progress.left.store(left, Ordering::Relaxed);
+
+        let num_have = have.iter().filter(|&&have| have).count();
+        if num_have > 0 {
+            println!(
+                "Resuming with {}/{} pieces already downloaded",
+                num_have, num_pieces
+            );
+        }
+        Ok(Self {
+            output: output.to_path_buf(),
+            options,
+            shared: Arc::new(SharedTorrent::new(torrent.clone(), storage, &have)),
+            have,
+        })
+    }
+
+    /// The local data, which keeps being served after the download, when seeding it.
+    pub(crate) fn shared(&self) -> Arc<SharedTorrent> {
+        Arc::clone(&self.shared)
+    }
+
+    /// Downloads the missing pieces from `peers`, and from the peers that arrive on
+    /// `more_peers` while it runs, up to `max_peers` at a time. Peers wait in line for a
+    /// free slot, peers already tried are skipped. Gives up once every peer is done and no
+    /// new ones are waiting. Peers get the pieces we have as well, as far as the choker
+    /// lets them.
+    pub(crate) async fn run(
+        mut self,
+        peers: &[SocketAddr],
+        mut more_peers: mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+        peer_id: [u8; 20],
+        progress: &Progress,
+    ) -> Result<DownloadStats, Error> {
+        let torrent = &self.shared.torrent;
+        let num_pieces = torrent.num_pieces();
+        let mut remaining = self.have.iter().filter(|&&have| !have).count();
+        if remaining == 0 {
+            resume::save(torrent, &self.output, &self.have)?;
+            return Ok(DownloadStats::default());
+        }
+
+        let mut piece_picker = PiecePicker::new(num_pieces);
+        for piece_index in (0..num_pieces).filter(|&i| self.have[i]) {
+            piece_picker.complete(piece_index);
+        }
+        let options = self.options;
+        let shared_state = Arc::new(Workers {
+            shared: Arc::clone(&self.shared),
+            picker: SharedPicker {
+                picker: Mutex::new(piece_picker),
+                changed: Notify::new(),
+            },
+            choked: Arc::new(ChokedPeers::new(options.choker_config)),
+            stats: Mutex::new(DownloadStats::default()),
+            peer_id,
+            options,
+        });
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(usize, Vec<u8>)>();
+        // Aborted when the download returns.
+        let mut choker = JoinSet::new();
+        let choked = Arc::clone(&shared_state.choked);
+        choker.spawn(async move { choked.run(Ranking::DownloadRate).await });
+
+        let mut tried = HashSet::new();
+        let mut untried = VecDeque::new();
+        let mut add_peers = |untried: &mut VecDeque<SocketAddr>, peers: &[SocketAddr]| {
+            untried.extend(peers.iter().filter(|&&peer_addr| tried.insert(peer_addr)));
+        };
+        let mut workers = JoinSet::new();
+        let spawn_workers = |workers: &mut JoinSet<()>, untried: &mut VecDeque<SocketAddr>| {
+            while workers.len() < options.max_peers {
+                let Some(peer_addr) = untried.pop_front() else {
+                    break;
+                };
+                let shared_state = Arc::clone(&shared_state);
+                let sender = sender.clone();
+                workers.spawn(async move {
+                    let result = peer_worker(&shared_state, peer_addr, sender).await;
+                    if let Err(e) = result {
+                        eprintln!("Peer {} failed: {}", peer_addr, e);
+                    }
+                });
+            }
+        };
+        add_peers(&mut untried, peers);
+        spawn_workers(&mut workers, &mut untried);
+
+        let mut last_save = Instant::now();
+        while remaining > 0 {
+            let (piece_index, piece) = if workers.is_empty() {
+                // Only pieces sent by workers that have exited, or new peers, can still help.
+                if let Ok(piece) = receiver.try_recv() {
+                    piece
+                } else if let Ok(peers) = more_peers.try_recv() {
+                    add_peers(&mut untried, &peers);
+                    spawn_workers(&mut workers, &mut untried);
+                    continue;
+                } else {
+                    break;
+                }
+            } else {
+                tokio::select! {
+                    Some(piece) = receiver.recv() => piece,
+                    Some(peers) = more_peers.recv() => {
+                        add_peers(&mut untried, &peers);
+                        spawn_workers(&mut workers, &mut untried);
+                        continue;
+                    }
+                    Some(_) = workers.join_next() => {
+                        spawn_workers(&mut workers, &mut untried);
+                        continue;
+                    }
+                }
+            };
+
+            if let Err(e) = self.shared.write_piece(piece_index, &piece) {
+                resume::save(torrent, &self.output, &self.have)?;
+                return Err(e);
+            }
+            self.have[piece_index] = true;
+            remaining -= 1;
+            let length = piece.len() as u64;
+            progress.downloaded.fetch_add(length, Ordering::Relaxed);
+            progress.left.fetch_sub(length, Ordering::Relaxed);
+            if last_save.elapsed() >= RESUME_SAVE_INTERVAL {
+                resume::save(torrent, &self.output, &self.have)?;
+                last_save = Instant::now();
+            }
+        }
+        while workers.join_next().await.is_some() {}
+        resume::save(torrent, &self.output, &self.have)?;
+
+        let stats = *shared_state.stats.lock().unwrap();
+        if stats.hash_failures > 0 {
+            eprintln!(
+                "{} pieces failed the hash check, {} peers banned",
+                stats.hash_failures, stats.banned_peers
+            );
+        }
+        if remaining > 0 {
+            return Err(Error::IncompleteDownload(remaining));
+        }
+        Ok(stats)
+    }
+}
+
+/// Downloads a torrent from a fixed set of peers.
+pub(crate) async fn download(
+    torrent: &Torrent,
+    peers: &[SocketAddr],
+    peer_id: [u8; 20],
+    output: &Path,
+    options: DownloadOptions,
+    progress: &Progress,
+) -> Result<DownloadStats, Error> {
+    let (_, no_more_peers) = mpsc::unbounded_channel();
+    Download::open(torrent, output, options, progress)?
+        .run(peers, no_more_peers, peer_id, progress)
+        .await
+}
+
+async fn peer_worker(
+    workers: &Workers,
+    peer_addr: SocketAddr,
+    sender: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+) -> Result<(), Error> {
+    let torrent = &workers.shared.torrent;
+    let mut peer = PeerConnection::connect(peer_addr, torrent.info_hash(), workers.peer_id).await?;
+    peer.pipeline_depth = workers.options.pipeline_depth;
+    peer.set_num_pieces(torrent.num_pieces());
+
+    // Pieces completing from here on are announced with `Have`, possibly twice.
+    let announced = workers.shared.completed_since(0).len();
+    if workers.shared.num_verified() > 0 {
+        peer.send(&PeerMessage::Bitfield(workers.shared.bitfield()))
+            .await?;
+    }
+    peer.upload_from(workers.shared.clone(), workers.choked.join());
+    peer.prepare_download().await?;
+
+    let mut known = vec![false; torrent.num_pieces()];
+    let result = download_from_peer(workers, &mut peer, &mut known, announced, sender).await;
+    workers.picker.forget_availability(&known);
+    result
+}
+
+async fn download_from_peer(
+    workers: &Workers,
+    peer: &mut PeerConnection,
+    known: &mut [bool],
+    mut announced: usize,
+    sender: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+) -> Result<(), Error> {
+    let (torrent, picker) = (&workers.shared.torrent, &workers.picker);
+    // Pieces this peer sent bad data for, left to the other peers.
+    let mut corrupt = HashSet::new();
+    loop {
+        for piece_index in workers.shared.completed_since(announced) {
+            peer.send(&PeerMessage::Have(piece_index as u32)).await?;
+            announced += 1;
+        }
+        picker.update_availability(peer, known);
+        let allowed = |i| peer.has_piece(i) && !corrupt.contains(&i);
+        let Some(piece_index) = picker.take(allowed).await else {
+            return Ok(());
+        };
+
+        // In endgame mode another peer may finish the piece first, then ours is cancelled.
+        let download = peer.download_piece_until(
+            piece_index,
+            torrent.piece_size(piece_index),
+            picker.completed(piece_index),
+        );
+        let piece = match download.await {
+            Ok(Some(piece)) => piece,
+            Ok(None) => {
+                picker.abort(piece_index);
+                continue;
+            }
+            // The peer dropped our requests. Leave the piece to others until it lets us in
+            // again, then pick anew.
+            Err(Error::Choked) => {
+                picker.abort(piece_index);
+                peer.expect_message(PeerMessageType::Unchoke).await?;
+                continue;
+            }
+            Err(e) => {
+                picker.abort(piece_index);
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = verify_piece(torrent, piece_index, &piece) {
+            // Discard the piece and let another peer download it.
+            picker.abort(piece_index);
+            corrupt.insert(piece_index);
+            let mut stats = workers.stats.lock().unwrap();
+            stats.hash_failures += 1;
+            if corrupt.len() >= MAX_CORRUPT_PIECES {
+                stats.banned_peers += 1;
+                return Err(Error::PeerBanned(corrupt.len()));
+            }
+            eprintln!("{}, requesting it from another peer", e);
+            continue;
+        }
+
+        if picker.complete(piece_index) && sender.send((piece_index, piece)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::SocketAddr};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, CreateOptions},
+        peer::{unexpected_message, PeerMessage, PiecePayload, RequestPayload},
+        seed::{Seeder, SharedTorrent},
+        storage::{MemoryStorage, Storage},
+    };
+
+    const PEER_ID: [u8; 20] = *b"-TEST-00000000000000";
+
+    async fn bind() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    /// A peer that has every piece and answers each request with zeros.
+    async fn serve_corrupt_data(listener: TcpListener, torrent: Torrent) -> Result<(), Error> {
+        let (stream, _) = listener.accept().await?;
+        let info_hash = torrent.info_hash();
+        let (mut peer, _) =
+            PeerConnection::accept(stream, PEER_ID, |hash| *hash == info_hash).await?;
+
+        let mut bitfield = vec![0u8; torrent.num_pieces().div_ceil(8)];
+        for piece_index in 0..torrent.num_pieces() {
+            bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        }
+        peer.send(&PeerMessage::Bitfield(bitfield)).await?;
+        loop {
+            match peer.read_message().await? {
+                PeerMessage::Interested => peer.send(&PeerMessage::Unchoke).await?,
+                PeerMessage::Request(request) => {
+                    let block = vec![0; request.length as usize];
+                    let payload = PiecePayload {
+                        index: request.index,
+                        begin: request.begin,
+                        block,
+                    };
+                    peer.send(&PeerMessage::Piece(payload)).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A peer that announces its pieces with `Have` instead of a bitfield, and chokes us
+    /// once, on our first request, before serving `data`.
+    async fn serve_choking_once(
+        listener: TcpListener,
+        torrent: Torrent,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        let (stream, _) = listener.accept().await?;
+        let info_hash = torrent.info_hash();
+        let (mut peer, _) =
+            PeerConnection::accept(stream, PEER_ID, |hash| *hash == info_hash).await?;
+
+        for piece_index in 0..torrent.num_pieces() {
+            peer.send(&PeerMessage::Have(piece_index as u32)).await?;
+        }
+        let mut choked = false;
+        loop {
+            match peer.read_message().await? {
+                PeerMessage::Interested => peer.send(&PeerMessage::Unchoke).await?,
+                PeerMessage::Request(_) if !choked => {
+                    choked = true;
+                    peer.send(&PeerMessage::Choke).await?;
+                    peer.send(&PeerMessage::Unchoke).await?;
+                }
+                PeerMessage::Request(request) => {
+                    let start = request.index as usize * 16 * 1024 + request.begin as usize;
+                    let block = data[start..start + request.length as usize].to_vec();
+                    let payload = PiecePayload {
+                        index: request.index,
+                        begin: request.begin,
+                        block,
+                    };
+                    peer.send(&PeerMessage::Piece(payload)).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A peer that has every piece but the first, which it fetches from us before it lets
+    /// us download the rest of `data`.
+    async fn serve_missing_first_piece(
+        listener: TcpListener,
+        torrent: Torrent,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        let (stream, _) = listener.accept().await?;
+        let info_hash = torrent.info_hash();
+        let (mut peer, _) =
+            PeerConnection::accept(stream, PEER_ID, |hash| *hash == info_hash).await?;
+        peer.set_num_pieces(torrent.num_pieces());
+
+        let mut bitfield = vec![0u8; torrent.num_pieces().div_ceil(8)];
+        for piece_index in 1..torrent.num_pieces() {
+            bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        }
+        peer.send(&PeerMessage::Bitfield(bitfield)).await?;
+        peer.read_bitfield().await?;
+        assert!(peer.has_piece(0));
+
+        // The downloader's choker has a free slot for us once we are interested.
+        peer.request_unchoke().await?;
+        let request = RequestPayload::new(0, 0, 16 * 1024);
+        peer.send(&PeerMessage::Request(request)).await?;
+        match peer.expect_message(PeerMessageType::Piece).await? {
+            PeerMessage::Piece(piece) => assert_eq!(piece.block, data[..16 * 1024]),
+            message => return Err(unexpected_message(PeerMessageType::Piece, &message)),
+        }
+
+        peer.send(&PeerMessage::Unchoke).await?;
+        loop {
+            if let PeerMessage::Request(request) = peer.read_message().await? {
+                let start = request.index as usize * 16 * 1024 + request.begin as usize;
+                let block = data[start..start + request.length as usize].to_vec();
+                let payload = PiecePayload {
+                    index: request.index,
+                    begin: request.begin,
+                    block,
+                };
+                peer.send(&PeerMessage::Piece(payload)).await?;
+            }
+        }
+    }
+
+    /// A torrent of five 16 KiB pieces, the last one short, and its data.
+    fn test_torrent(dir: &tempfile::TempDir) -> (Torrent, Vec<u8>) {
+        let source = dir.path().join("source.bin");
+        let data: Vec<u8> = (0..80_000u32).map(|i| (i % 249) as u8 + 1).collect();
+        fs::write(&source, &data).unwrap();
+        let options = CreateOptions {
+            announce: "http://tracker/announce".to_owned(),
+            announce_list: Vec::new(),
+            comment: None,
+            piece_length: Some(16 * 1024),
+            private: false,
+        };
+        (create_torrent(&source, &options).unwrap(), data)
+    }
+
+    /// A seeder holding all of `data` in memory.
+    fn honest_seeder(torrent: &Torrent, data: &[u8]) -> Seeder {
+        let mut storage = MemoryStorage::new(torrent);
+        for piece_index in 0..torrent.num_pieces() {
+            let start = piece_index * 16 * 1024;
+            let end = data.len().min(start + 16 * 1024);
+            storage.write_piece(piece_index, &data[start..end]).unwrap();
+        }
+        let shared = Arc::new(SharedTorrent::verify(torrent.clone(), storage).unwrap());
+        let mut seeder = Seeder::new(*b"-SEED-00000000000000");
+        seeder.add(shared);
+        seeder
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_pieces_are_downloaded_again_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = test_torrent(&dir);
+
+        // The corrupt peer serves until it is banned, which closes its connection.
+        let (corrupt_listener, corrupt_addr) = bind().await;
+        let (banned_sender, banned) = tokio::sync::oneshot::channel();
+        let corrupt_torrent = torrent.clone();
+        tokio::spawn(async move {
+            let _ = serve_corrupt_data(corrupt_listener, corrupt_torrent).await;
+            let _ = banned_sender.send(());
+        });
+
+        // The honest seeder only starts answering once the corrupt peer is banned.
+        let seeder = honest_seeder(&torrent, &data);
+        let (seeder_listener, seeder_addr) = bind().await;
+        tokio::spawn(async move {
+            let _ = banned.await;
+            Arc::new(seeder).listen(seeder_listener).await
+        });
+
+        let output = dir.path().join("output.bin");
+        let peers = [corrupt_addr, seeder_addr];
+        let progress = Progress::new(&torrent);
+        let options = DownloadOptions::default();
+        let stats = download(&torrent, &peers, PEER_ID, &output, options, &progress)
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert_eq!(progress.downloaded.into_inner(), data.len() as u64);
+        assert_eq!(progress.left.into_inner(), 0);
+        assert_eq!(
+            stats,
+            DownloadStats {
+                hash_failures: MAX_CORRUPT_PIECES,
+                banned_peers: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_choked_peer_without_bitfield_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = test_torrent(&dir);
+        let (listener, addr) = bind().await;
+        tokio::spawn(serve_choking_once(listener, torrent.clone(), data.clone()));
+
+        let output = dir.path().join("output.bin");
+        let progress = Progress::new(&torrent);
+        // One request in flight, so none is still on its way when the peer unchokes us.
+        let options = DownloadOptions {
+            pipeline_depth: 1,
+            ..DownloadOptions::default()
+        };
+        download(&torrent, &[addr], PEER_ID, &output, options, &progress)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_peers_past_max_peers_wait_for_a_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = test_torrent(&dir);
+        // Nothing listens on the first peer's port.
+        let (_, unreachable_addr) = bind().await;
+        let (seeder_listener, seeder_addr) = bind().await;
+        tokio::spawn(Arc::new(honest_seeder(&torrent, &data)).listen(seeder_listener));
+
+        let output = dir.path().join("output.bin");
+        let progress = Progress::new(&torrent);
+        let options = DownloadOptions {
+            max_peers: 1,
+            ..DownloadOptions::default()
+        };
+        let peers = [unreachable_addr, seeder_addr];
+        download(&torrent, &peers, PEER_ID, &output, options, &progress)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_resumed_download_with_late_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = test_torrent(&dir);
+
+        // The first piece is already on disk, the rest is missing.
+        let output = dir.path().join("output.bin");
+        let mut partial = data.clone();
+        partial[16 * 1024..].fill(0);
+        fs::write(&output, &partial).unwrap();
+
+        let progress = Progress::new(&torrent);
+        let options = DownloadOptions::default();
+        let download = Download::open(&torrent, &output, options, &progress).unwrap();
+        let missing = (data.len() - 16 * 1024) as u64;
+        assert_eq!(progress.left.load(Ordering::Relaxed), missing);
+
+        // No peers to start with, one turns up later.
+        let (seeder_listener, seeder_addr) = bind().await;
+        tokio::spawn(Arc::new(honest_seeder(&torrent, &data)).listen(seeder_listener));
+        let (more_peers, more_peers_receiver) = mpsc::unbounded_channel();
+        more_peers.send(vec![seeder_addr]).unwrap();
+        download
+            .run(&[], more_peers_receiver, PEER_ID, &progress)
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert_eq!(progress.downloaded.into_inner(), missing);
+        assert_eq!(progress.left.into_inner(), 0);
+
+        // The state saved when the download ended covers every piece.
+        let reopened = Progress::new(&torrent);
+        let download = Download::open(&torrent, &output, options, &reopened).unwrap();
+        assert!(download.have.iter().all(|&have| have));
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_served_while_downloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = test_torrent(&dir);
+
+        // We start out with the first piece only.
+        let output = dir.path().join("output.bin");
+        let mut partial = data.clone();
+        partial[16 * 1024..].fill(0);
+        fs::write(&output, &partial).unwrap();
+
+        let (listener, addr) = bind().await;
+        let peer = tokio::spawn(serve_missing_first_piece(
+            listener,
+            torrent.clone(),
+            data.clone(),
+        ));
+
+        let progress = Progress::new(&torrent);
+        let options = DownloadOptions::default();
+        let download = Download::open(&torrent, &output, options, &progress).unwrap();
+        let shared = download.shared();
+        let (_, no_more_peers) = mpsc::unbounded_channel();
+        download
+            .run(&[addr], no_more_peers, PEER_ID, &progress)
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert_eq!(shared.uploaded.load(Ordering::Relaxed), 16 * 1024);
+        // The peer only ends when we hang up.
+        assert!(peer.await.unwrap().is_err());
+    }
+}
@@ -1,37 +1,23 @@
-use serde_json::Value;
-
+use std::collections::BTreeMap;
 use std::io::Write;
 
-use crate::Error;
+use crate::{bencode::Bencode, Error};
 
 pub(crate) struct Encoder;
 
 impl Encoder {
-    pub(crate) fn encode(value: &Value) -> Result<Vec<u8>, Error> {
+    pub(crate) fn encode(value: &Bencode) -> Result<Vec<u8>, Error> {
         let mut output = Vec::new();
         Self::encode_value(value, &mut output)?;
         Ok(output)
     }
 
-    fn encode_value(value: &Value, output: &mut Vec<u8>) -> Result<(), Error> {
+    fn encode_value(value: &Bencode, output: &mut Vec<u8>) -> Result<(), Error> {
         match value {
-            Value::String(s) => Self::encode_string(s.as_bytes(), output),
-            Value::Number(n) => Self::encode_integer(n, output),
-            Value::Array(a) => {
-                if a.iter().all(|v| v.is_u64()) {
-                    // Treat array of numbers as binary data
-                    let bytes: Vec<u8> = a
-                        .iter()
-                        .filter_map(|v| v.as_u64().map(|n| n as u8))
-                        .collect();
-                    Self::encode_string(&bytes, output)
-                } else {
-                    Self::encode_list(a, output)
-                }
-            }
-            Value::Object(o) => Self::encode_dict(o, output),
-            Value::Bool(b) => Self::encode_string(b.to_string().as_bytes(), output),
-            Value::Null => Self::encode_string("null".as_bytes(), output),
+            Bencode::Bytes(bytes) => Self::encode_string(bytes, output),
+            Bencode::Int(n) => Self::encode_integer(*n, output),
+            Bencode::List(list) => Self::encode_list(list, output),
+            Bencode::Dict(dict) => Self::encode_dict(dict, output),
         }
     }
 
@@ -41,12 +27,12 @@ impl Encoder {
         Ok(())
     }
 
-    fn encode_integer(n: &serde_json::Number, output: &mut Vec<u8>) -> Result<(), Error> {
+    fn encode_integer(n: i64, output: &mut Vec<u8>) -> Result<(), Error> {
         write!(output, "i{}e", n)?;
         Ok(())
     }
 
-    fn encode_list(arr: &[Value], output: &mut Vec<u8>) -> Result<(), Error> {
+    fn encode_list(arr: &[Bencode], output: &mut Vec<u8>) -> Result<(), Error> {
         output.push(b'l');
         for item in arr {
             Self::encode_value(item, output)?;
@@ -55,18 +41,34 @@ impl Encoder {
         Ok(())
     }
 
-    fn encode_dict(
-        obj: &serde_json::Map<String, Value>,
-        output: &mut Vec<u8>,
-    ) -> Result<(), Error> {
+    fn encode_dict(dict: &BTreeMap<Vec<u8>, Bencode>, output: &mut Vec<u8>) -> Result<(), Error> {
         output.push(b'd');
-        let mut keys: Vec<&String> = obj.keys().collect();
-        keys.sort();
-        for key in keys {
-            Self::encode_string(key.as_bytes(), output)?;
-            Self::encode_value(&obj[key], output)?;
+        // BTreeMap iterates keys in raw byte order, as bencode requires.
+        for (key, value) in dict {
+            Self::encode_string(key, output)?;
+            Self::encode_value(value, output)?;
         }
         output.push(b'e');
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+
+    #[test]
+    fn test_bencode_round_trip() {
+        let input = b"d4:listli1ei2ei3ee6:pieces4:\xde\xad\xbe\xef3:strl3:fooee";
+        let mut bencode_decoder = Decoder::new(input);
+        let decoded_value = bencode_decoder.decode().unwrap();
+        assert_eq!(Encoder::encode(&decoded_value).unwrap(), input);
+    }
+
+    #[test]
+    fn test_integer_list_stays_a_list() {
+        let value = Bencode::List(vec![Bencode::Int(104), Bencode::Int(105)]);
+        assert_eq!(Encoder::encode(&value).unwrap(), b"li104ei105ee");
+    }
+}
@@ -15,6 +15,43 @@ pub(crate) enum Error {
     MissingField(String),
     InvalidMessageType(u8),
     UnexpectedPeerMessage(u8, u8),
+    InvalidMessagePayload(u8),
+    MessageTooLarge(u32),
+    Choked,
+    InvalidHandshake,
+    UnknownInfoHash(String),
+    PieceHashMismatch(usize),
+    IncompleteDownload(usize),
+    PeerBanned(usize),
+    InvalidTrackerUrl(String),
+    InvalidTrackerResponse(String),
+    TrackerFailure(String),
+    TrackerWarning(String),
+    TrackerStatus(u16, String),
+    TrackerTimeout,
+    ScrapeNotSupported(String),
+    InvalidHttpRequest(String),
+    DhtTimeout,
+    DhtRejected(i64, String),
+    InvalidDhtMessage(String),
+    DhtBootstrapFailed,
+    NonCanonical(usize, crate::decoder::NonCanonical),
+    NestingTooDeep(usize),
+    StringTooLong(usize),
+    InvalidPath(String),
+    InvalidMagnetLink(String),
+    ExtensionsNotSupported,
+    InvalidExtensionMessage,
+    MetadataRejected(usize),
+    MetadataHashMismatch,
+    InvalidPieceLength(u64),
+    InvalidTorrent(String),
+    Custom(String),
+    Bencode {
+        offset: usize,
+        path: String,
+        message: String,
+    },
 }
 
 impl std::fmt::Display for Error {
@@ -47,6 +84,76 @@ impl std::fmt::Display for Error {
                     expected, actual
                 )
             }
+            Error::InvalidMessagePayload(message_type) => {
+                write!(f, "Invalid payload for message type {}", message_type)
+            }
+            Error::MessageTooLarge(length) => {
+                write!(f, "Peer message of {} bytes is too large", length)
+            }
+            Error::Choked => write!(f, "Choked by peer"),
+            Error::InvalidHandshake => write!(f, "Invalid handshake"),
+            Error::UnknownInfoHash(info_hash) => write!(f, "Unknown info hash {}", info_hash),
+            Error::PieceHashMismatch(piece_index) => {
+                write!(f, "Hash mismatch for piece {}", piece_index)
+            }
+            Error::IncompleteDownload(missing) => {
+                write!(f, "Download incomplete: {} pieces missing", missing)
+            }
+            Error::PeerBanned(corrupt) => {
+                write!(f, "Banned after sending {} corrupt pieces", corrupt)
+            }
+            Error::InvalidTrackerUrl(url) => write!(f, "Invalid tracker URL: {}", url),
+            Error::InvalidTrackerResponse(reason) => {
+                write!(f, "Invalid tracker response: {}", reason)
+            }
+            Error::TrackerFailure(reason) => write!(f, "Tracker failure: {}", reason),
+            Error::TrackerWarning(message) => write!(f, "Tracker warning: {}", message),
+            Error::TrackerStatus(status, body) => {
+                write!(f, "Tracker returned HTTP {}: {}", status, body)
+            }
+            Error::TrackerTimeout => write!(f, "Tracker did not respond"),
+            Error::InvalidHttpRequest(reason) => write!(f, "Invalid HTTP request: {}", reason),
+            Error::DhtTimeout => write!(f, "DHT node did not respond"),
+            Error::DhtRejected(code, message) => {
+                write!(f, "DHT node rejected the query: {} {}", code, message)
+            }
+            Error::InvalidDhtMessage(reason) => write!(f, "Invalid DHT message: {}", reason),
+            Error::DhtBootstrapFailed => write!(f, "Couldn't reach any DHT node"),
+            Error::ScrapeNotSupported(url) => write!(f, "Tracker {} doesn't support scrape", url),
+            Error::NonCanonical(offset, reason) => {
+                write!(f, "Non-canonical bencode at byte {}: {}", offset, reason)
+            }
+            Error::NestingTooDeep(max_depth) => {
+                write!(f, "Nesting deeper than {} levels", max_depth)
+            }
+            Error::StringTooLong(length) => write!(f, "String of {} bytes is too long", length),
+            Error::InvalidMagnetLink(link) => write!(f, "Invalid magnet link: {}", link),
+            Error::ExtensionsNotSupported => {
+                write!(f, "Peer does not support the metadata extension")
+            }
+            Error::InvalidExtensionMessage => write!(f, "Invalid extension message"),
+            Error::MetadataRejected(piece) => {
+                write!(f, "Peer rejected request for metadata piece {}", piece)
+            }
+            Error::MetadataHashMismatch => write!(f, "Metadata does not match the info hash"),
+            Error::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            Error::InvalidPieceLength(length) => write!(f, "Invalid piece length: {}", length),
+            Error::InvalidTorrent(reason) => write!(f, "Invalid torrent: {}", reason),
+            Error::Custom(message) => write!(f, "{}", message),
+            Error::Bencode {
+                offset,
+                path,
+                message,
+            } if path.is_empty() => write!(f, "Bencode error at byte {}: {}", offset, message),
+            Error::Bencode {
+                offset,
+                path,
+                message,
+            } => write!(
+                f,
+                "Bencode error at byte {} ({}): {}",
+                offset, path, message
+            ),
         }
     }
 }
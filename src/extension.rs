@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+
+use crate::{
+    deserializer::{self, Deserializer},
+    peer::{unexpected_message, PeerConnection, PeerMessage, PeerMessageType},
+    serializer, Error,
+};
+
+/// Extended message id of the extension handshake itself.
+const HANDSHAKE_ID: u8 = 0;
+/// Id we ask peers to use when sending us `ut_metadata` messages.
+const LOCAL_UT_METADATA_ID: u8 = 16;
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Upper bound on an info dict we are willing to fetch from a peer.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct ExtensionHandshake {
+    /// Extension names mapped to the message ids the sender wants to receive them with.
+    pub(crate) m: BTreeMap<String, u8>,
+    pub(crate) metadata_size: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    total_size: Option<usize>,
+}
+
+const METADATA_REQUEST: u8 = 0;
+const METADATA_DATA: u8 = 1;
+const METADATA_REJECT: u8 = 2;
+
+impl PeerConnection {
+    pub(crate) async fn send_extended<T: Serialize>(
+        &mut self,
+        extension_id: u8,
+        message: &T,
+    ) -> Result<(), Error> {
+        let payload = serializer::to_bytes(message)?;
+        self.send(&PeerMessage::Extended(extension_id, payload))
+            .await
+    }
+
+    /// Reads the next extended message, returning its extension id and payload.
+    pub(crate) async fn read_extended(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        match self.expect_message(PeerMessageType::Extended).await? {
+            PeerMessage::Extended(extension_id, payload) => Ok((extension_id, payload)),
+            message => Err(unexpected_message(PeerMessageType::Extended, &message)),
+        }
+    }
+
+    /// Exchanges extension handshakes, returning the one the peer sent.
+    pub(crate) async fn extension_handshake(&mut self) -> Result<ExtensionHandshake, Error> {
+        if !self.supports_extensions {
+            return Err(Error::ExtensionsNotSupported);
+        }
+
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_owned(), LOCAL_UT_METADATA_ID)]),
+            metadata_size: None,
+        };
+        self.send_extended(HANDSHAKE_ID, &handshake).await?;
+
+        let (extension_id, payload) = self.read_extended().await?;
+        if extension_id != HANDSHAKE_ID {
+            return Err(Error::InvalidExtensionMessage);
+        }
+        deserializer::from_bytes(&payload)
+    }
+
+    /// Fetches the info dict from the peer with `ut_metadata` (BEP 9) and verifies it
+    /// against `info_hash`. Returns the raw bencoded info dict.
+    pub(crate) async fn fetch_metadata(&mut self, info_hash: [u8; 20]) -> Result<Vec<u8>, Error> {
+        let handshake = self.extension_handshake().await?;
+        let ut_metadata_id = *handshake
+            .m
+            .get("ut_metadata")
+            .ok_or(Error::ExtensionsNotSupported)?;
+        let metadata_size = handshake
+            .metadata_size
+            .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+            .ok_or(Error::InvalidExtensionMessage)?;
+
+        let mut metadata = Vec::with_capacity(metadata_size);
+        let num_pieces = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+        for piece in 0..num_pieces {
+            let request = MetadataMessage {
+                msg_type: METADATA_REQUEST,
+                piece,
+                total_size: None,
+            };
+            self.send_extended(ut_metadata_id, &request).await?;
+
+            let (extension_id, payload) = self.read_extended().await?;
+            if extension_id != LOCAL_UT_METADATA_ID {
+                return Err(Error::InvalidExtensionMessage);
+            }
+
+            // The piece data follows directly after the bencoded dict.
+            let mut deserializer = Deserializer::new(&payload);
+            let response: MetadataMessage = deserializer.deserialize()?;
+            match response.msg_type {
+                METADATA_DATA if response.piece == piece => {
+                    metadata.extend_from_slice(&payload[deserializer.offset()..]);
+                }
+                METADATA_REJECT => return Err(Error::MetadataRejected(piece)),
+                _ => return Err(Error::InvalidExtensionMessage),
+            }
+        }
+
+        if metadata.len() != metadata_size {
+            return Err(Error::InvalidExtensionMessage);
+        }
+        if <[u8; 20]>::from(sha1::Sha1::digest(&metadata)) != info_hash {
+            return Err(Error::MetadataHashMismatch);
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::handshake::Handshake;
+
+    /// Id the test peer asks us to use for its `ut_metadata` messages.
+    const REMOTE_UT_METADATA_ID: u8 = 3;
+
+    /// Accepts one connection and serves `metadata` to it over `ut_metadata`.
+    async fn spawn_metadata_peer(metadata: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = Handshake::new([0; 20], [0; 20]);
+            stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+            assert!(handshake.supports_extensions());
+            let mut reply = Handshake::new(handshake.info_hash, [1; 20]).with_extensions();
+            stream.write_all(reply.as_bytes_mut()).await.unwrap();
+
+            let mut peer = PeerConnection::new(stream);
+            let (extension_id, payload) = peer.read_extended().await.unwrap();
+            assert_eq!(extension_id, HANDSHAKE_ID);
+            let handshake: ExtensionHandshake = deserializer::from_bytes(&payload).unwrap();
+            let local_id = handshake.m["ut_metadata"];
+            let handshake = ExtensionHandshake {
+                m: BTreeMap::from([("ut_metadata".to_owned(), REMOTE_UT_METADATA_ID)]),
+                metadata_size: Some(metadata.len()),
+            };
+            peer.send_extended(HANDSHAKE_ID, &handshake).await.unwrap();
+
+            while let Ok((extension_id, payload)) = peer.read_extended().await {
+                assert_eq!(extension_id, REMOTE_UT_METADATA_ID);
+                let request: MetadataMessage = deserializer::from_bytes(&payload).unwrap();
+                assert_eq!(request.msg_type, METADATA_REQUEST);
+                let start = request.piece * METADATA_PIECE_SIZE;
+                let end = metadata.len().min(start + METADATA_PIECE_SIZE);
+                let response = MetadataMessage {
+                    msg_type: METADATA_DATA,
+                    piece: request.piece,
+                    total_size: Some(metadata.len()),
+                };
+                let mut payload = serializer::to_bytes(&response).unwrap();
+                payload.extend_from_slice(&metadata[start..end]);
+                peer.send(&PeerMessage::Extended(local_id, payload))
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    fn metadata() -> (Vec<u8>, [u8; 20]) {
+        // Larger than one metadata piece so the fetch takes several requests.
+        let mut metadata = b"d6:pieces20000:".to_vec();
+        metadata.extend(vec![b'x'; 20000]);
+        metadata.push(b'e');
+        let info_hash = sha1::Sha1::digest(&metadata).into();
+        (metadata, info_hash)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata() {
+        let (metadata, info_hash) = metadata();
+        let addr = spawn_metadata_peer(metadata.clone()).await;
+
+        let mut peer = PeerConnection::connect_with_extensions(addr, info_hash, [2; 20])
+            .await
+            .unwrap();
+        assert_eq!(peer.fetch_metadata(info_hash).await.unwrap(), metadata);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_hash_mismatch() {
+        let (metadata, _) = metadata();
+        let addr = spawn_metadata_peer(metadata).await;
+
+        let info_hash = [9; 20];
+        let mut peer = PeerConnection::connect_with_extensions(addr, info_hash, [2; 20])
+            .await
+            .unwrap();
+        assert!(matches!(
+            peer.fetch_metadata(info_hash).await,
+            Err(Error::MetadataHashMismatch)
+        ));
+    }
+}
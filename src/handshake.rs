@@ -1,3 +1,5 @@
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
 #[repr(C)]
 pub struct Handshake {
     pub length: u8,
@@ -18,6 +20,16 @@ impl Handshake {
         }
     }
 
+    /// Advertises support for the extension protocol (BEP 10) in the reserved bytes.
+    pub fn with_extensions(mut self) -> Self {
+        self.reserved[5] |= EXTENSION_PROTOCOL_BIT;
+        self
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8; std::mem::size_of::<Handshake>()] {
         unsafe { &mut *(self as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()]) }
     }
@@ -0,0 +1,132 @@
+use std::net::SocketAddr;
+
+use crate::Error;
+
+/// A parsed `magnet:?xt=urn:btih:...` link.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct MagnetLink {
+    pub(crate) info_hash: [u8; 20],
+    /// Display name (`dn`).
+    pub(crate) name: Option<String>,
+    /// Tracker URLs (`tr`), in the order they appear.
+    pub(crate) trackers: Vec<String>,
+    /// Peer addresses (`x.pe`).
+    pub(crate) peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub(crate) fn parse(link: &str) -> Result<Self, Error> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| Error::InvalidMagnetLink(link.to_owned()))?;
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query)
+            .map_err(|_| Error::InvalidMagnetLink(link.to_owned()))?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    // Only BitTorrent info hashes are supported, other URNs are skipped.
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(
+                    value
+                        .parse()
+                        .map_err(|_| Error::InvalidMagnetLink(value.clone()))?,
+                ),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or(Error::MissingField("xt".to_owned()))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// Decodes a 40-character hex or 32-character base32 info hash.
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], Error> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => base32_decode(hash),
+        _ => None,
+    };
+
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidMagnetLink(hash.to_owned()))
+}
+
+/// Decodes unpadded RFC 4648 base32, as used by older magnet links.
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_magnet_link() {
+        let link = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif\
+                    &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+                    &x.pe=127.0.0.1:6881";
+        let magnet = MagnetLink::parse(link).unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.trackers,
+            ["http://bittorrent-test-tracker.codecrafters.io/announce"]
+        );
+        assert_eq!(magnet.peers, ["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_base32_magnet_link() {
+        let hex_magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165")
+                .unwrap();
+        let base32_magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+        assert_eq!(base32_magnet.info_hash, hex_magnet.info_hash);
+    }
+
+    #[test]
+    fn test_parse_invalid_magnet_link() {
+        assert!(MagnetLink::parse("http://example.com").is_err());
+        assert!(MagnetLink::parse("magnet:?dn=name").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+    }
+}
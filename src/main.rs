@@ -1,20 +1,40 @@
 use clap::{Parser, Subcommand};
-use peer::{PeerConnection, PeerMessageType, PiecePayload, RequestPayload};
-use sha1::Digest;
+use peer::PeerConnection;
 use std::{
     fs,
-    io::{Read, Write},
-    net::SocketAddrV4,
-    path::PathBuf,
+    io::Read,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
+use storage::Storage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+mod bencode;
+mod choke;
+mod create;
 mod decoder;
+mod deserializer;
+mod dht;
+mod download;
 mod encoder;
 mod error;
+mod extension;
 mod handshake;
+mod magnet;
 mod peer;
+mod picker;
+mod random;
+mod resume;
+mod seed;
+mod serializer;
+mod storage;
+mod stream_decoder;
 mod torrent;
 mod tracker;
+mod tracker_server;
+mod udp_tracker;
 
 pub(crate) use error::*;
 use torrent::Torrent;
@@ -29,13 +49,28 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Decode {
+        /// Bencoded value, or `-` to stream values from stdin
         encoded_value: String,
+        /// Maximum nesting of lists and dicts when reading from stdin
+        #[arg(long, default_value_t = stream_decoder::Limits::default().max_depth)]
+        max_depth: usize,
+        /// Maximum byte string length when reading from stdin
+        #[arg(long, default_value_t = stream_decoder::Limits::default().max_string_length)]
+        max_string_length: usize,
+    },
+    Validate {
+        /// Fail on the first non-canonical construct instead of listing them all
+        #[arg(long)]
+        strict: bool,
+        file_path: PathBuf,
     },
     Info {
         file_path: PathBuf,
     },
     Peers {
         file_path: PathBuf,
+        #[command(flatten)]
+        dht: DhtArgs,
     },
     Handshake {
         torrent_file: PathBuf,
@@ -48,49 +83,338 @@ enum Commands {
         torrent: PathBuf,
         piece: usize,
     },
+    Download {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        #[command(flatten)]
+        options: DownloadArgs,
+        #[command(flatten)]
+        dht: DhtArgs,
+        /// Keep serving the file to other peers once it is complete
+        #[arg(long)]
+        seed: bool,
+    },
+    #[command(name = "magnet_parse")]
+    MagnetParse {
+        magnet_link: String,
+    },
+    #[command(name = "magnet_info")]
+    MagnetInfo {
+        magnet_link: String,
+        #[command(flatten)]
+        dht: DhtArgs,
+    },
+    #[command(name = "magnet_download")]
+    MagnetDownload {
+        #[arg(short)]
+        output: PathBuf,
+        magnet_link: String,
+        #[command(flatten)]
+        options: DownloadArgs,
+        #[command(flatten)]
+        dht: DhtArgs,
+    },
+    /// Hash the local data of a torrent and report which pieces are valid
+    Recheck {
+        torrent: PathBuf,
+        /// The downloaded file, or directory for multi-file torrents
+        path: PathBuf,
+    },
+    /// Ask the trackers for the swarm size of torrents without joining them
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+    },
+    Seed {
+        torrent: PathBuf,
+        /// The downloaded file, or directory for multi-file torrents
+        path: PathBuf,
+        #[arg(long, default_value_t = tracker::DEFAULT_PORT)]
+        port: u16,
+        /// Peers unchoked for their upload rate, besides the optimistic unchoke
+        #[arg(long, default_value_t = choke::ChokerConfig::default().regular_slots)]
+        upload_slots: usize,
+    },
+    /// Run an HTTP tracker
+    #[command(name = "tracker-serve")]
+    TrackerServe {
+        #[arg(long, default_value_t = 6969)]
+        port: u16,
+        /// Seconds between announces asked of clients
+        #[arg(long, default_value_t = tracker_server::TrackerServerConfig::default().interval.as_secs())]
+        interval: u64,
+        /// Only track this torrent; repeat for more, all torrents are tracked if omitted
+        #[arg(long = "allow")]
+        allow: Vec<PathBuf>,
+    },
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        #[arg(long)]
+        announce: String,
+        /// A tier of comma-separated tracker URLs; repeat for more tiers
+        #[arg(long = "announce-list")]
+        announce_list: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        /// Piece length in bytes, picked from the content size if omitted
+        #[arg(long)]
+        piece_length: Option<u64>,
+        #[arg(long)]
+        private: bool,
+        path: PathBuf,
+    },
+}
+
+#[derive(clap::Args)]
+struct DownloadArgs {
+    /// Maximum number of peers to download from at once
+    #[arg(long, default_value_t = download::DownloadOptions::default().max_peers)]
+    max_peers: usize,
+    /// Outstanding block requests per peer
+    #[arg(long, default_value_t = download::DownloadOptions::default().pipeline_depth)]
+    pipeline: usize,
+    /// How disk space is reserved before downloading
+    #[arg(long, value_enum, default_value_t = storage::Preallocation::default())]
+    preallocate: storage::Preallocation,
+}
+
+#[derive(clap::Args)]
+struct DhtArgs {
+    /// Also find peers through the DHT; always on for torrents without trackers
+    #[arg(long)]
+    dht: bool,
+    /// UDP port of the DHT node
+    #[arg(long, default_value_t = tracker::DEFAULT_PORT)]
+    dht_port: u16,
+    /// DHT node to join through, as host:port; repeat for more
+    #[arg(long, default_values = dht::DEFAULT_BOOTSTRAP_NODES)]
+    dht_bootstrap: Vec<String>,
+    /// File keeping the DHT routing table between runs
+    #[arg(long)]
+    dht_state: Option<PathBuf>,
 }
 
-fn main() -> Result<(), Error> {
+impl DhtArgs {
+    /// Joins the DHT if asked to, or if there is no other way to get peers. `nodes` are
+    /// tried before the bootstrap nodes.
+    async fn start(
+        &self,
+        has_sources: bool,
+        nodes: &[String],
+    ) -> Result<Option<Arc<dht::Dht>>, crate::Error> {
+        if !self.dht && has_sources {
+            return Ok(None);
+        }
+        let mut bootstrap = nodes.to_vec();
+        bootstrap.extend(self.dht_bootstrap.iter().cloned());
+        let config = dht::DhtConfig {
+            bootstrap,
+            state_path: self.dht_state.clone(),
+            ..Default::default()
+        };
+        let dht = dht::Dht::bind(self.dht_port, config).await?;
+        match dht.bootstrap().await {
+            Ok(()) => Ok(Some(dht)),
+            Err(e) if has_sources => {
+                eprintln!("Continuing without the DHT: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Joins the DHT for a torrent. Private torrents only get peers from their trackers.
+    async fn start_for(&self, torrent: &Torrent) -> Result<Option<Arc<dht::Dht>>, crate::Error> {
+        if torrent.info.private == Some(1) {
+            return Ok(None);
+        }
+        let has_trackers = !tracker::TrackerList::from_torrent(torrent).is_empty();
+        let nodes: Vec<String> = torrent
+            .nodes
+            .iter()
+            .flatten()
+            .map(|(host, port)| format!("{}:{}", host, port))
+            .collect();
+        self.start(has_trackers, &nodes).await
+    }
+}
+
+impl From<&DownloadArgs> for download::DownloadOptions {
+    fn from(args: &DownloadArgs) -> Self {
+        Self {
+            max_peers: args.max_peers,
+            pipeline_depth: args.pipeline,
+            preallocation: args.preallocate,
+            ..Default::default()
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Decode { encoded_value } => handle_decode_command(encoded_value),
+        Commands::Decode {
+            encoded_value,
+            max_depth,
+            max_string_length,
+        } => {
+            if encoded_value == "-" {
+                let limits = stream_decoder::Limits {
+                    max_depth: *max_depth,
+                    max_string_length: *max_string_length,
+                };
+                handle_decode_stdin_command(limits)
+            } else {
+                handle_decode_command(encoded_value)
+            }
+        }
+        Commands::Validate { strict, file_path } => handle_validate_command(file_path, *strict),
         Commands::Info { file_path } => handle_info_command(file_path),
-        Commands::Peers { file_path } => handle_peers_command(file_path),
+        Commands::Peers { file_path, dht } => handle_peers_command(file_path, dht).await,
         Commands::Handshake {
             torrent_file,
             peer_address,
-        } => handle_handshake_command(torrent_file, peer_address),
+        } => handle_handshake_command(torrent_file, peer_address).await,
         Commands::DownloadPiece {
             output,
             torrent,
             piece,
-        } => handle_download_piece_command(output, torrent, *piece),
+        } => handle_download_piece_command(output, torrent, *piece).await,
+        Commands::Download {
+            output,
+            torrent,
+            options,
+            dht,
+            seed,
+        } => handle_download_command(output, torrent, options.into(), dht, *seed).await,
+        Commands::MagnetParse { magnet_link } => handle_magnet_parse_command(magnet_link),
+        Commands::MagnetInfo { magnet_link, dht } => {
+            handle_magnet_info_command(magnet_link, dht).await
+        }
+        Commands::MagnetDownload {
+            output,
+            magnet_link,
+            options,
+            dht,
+        } => handle_magnet_download_command(output, magnet_link, options.into(), dht).await,
+        Commands::Recheck { torrent, path } => handle_recheck_command(torrent, path),
+        Commands::Scrape { torrents } => handle_scrape_command(torrents).await,
+        Commands::Seed {
+            torrent,
+            path,
+            port,
+            upload_slots,
+        } => {
+            let choker_config = choke::ChokerConfig {
+                regular_slots: *upload_slots,
+                ..Default::default()
+            };
+            handle_seed_command(torrent, path, *port, choker_config).await
+        }
+        Commands::TrackerServe {
+            port,
+            interval,
+            allow,
+        } => handle_tracker_serve_command(*port, *interval, allow).await,
+        Commands::Create {
+            output,
+            announce,
+            announce_list,
+            comment,
+            piece_length,
+            private,
+            path,
+        } => {
+            let options = create::CreateOptions {
+                announce: announce.clone(),
+                announce_list: announce_list
+                    .iter()
+                    .map(|tier| tier.split(',').map(str::to_owned).collect())
+                    .collect(),
+                comment: comment.clone(),
+                piece_length: *piece_length,
+                private: *private,
+            };
+            handle_create_command(output, path, &options)
+        }
     }
 }
 
 fn handle_decode_command(encoded_value: &str) -> Result<(), crate::Error> {
     let mut bencode_decoder = decoder::Decoder::new(encoded_value.as_bytes());
     let decoded_value = bencode_decoder.decode()?;
-    println!("{}", decoded_value);
+    println!("{}", decoded_value.to_json());
+    Ok(())
+}
+
+fn handle_decode_stdin_command(limits: stream_decoder::Limits) -> Result<(), crate::Error> {
+    let mut stream_decoder = stream_decoder::StreamDecoder::new(std::io::stdin().lock(), limits);
+    while let Some(decoded_value) = stream_decoder.decode()? {
+        println!("{}", decoded_value.to_json());
+    }
+    Ok(())
+}
+
+fn handle_validate_command(file_path: &PathBuf, strict: bool) -> Result<(), crate::Error> {
+    let buffer = read_file(file_path)?;
+    let mode = if strict {
+        decoder::DecodeMode::Strict
+    } else {
+        decoder::DecodeMode::Lenient
+    };
+    let mut bencode_decoder = decoder::Decoder::with_mode(&buffer, mode);
+    bencode_decoder.decode()?;
+
+    for warning in bencode_decoder.warnings() {
+        println!("byte {}: {}", warning.offset, warning.reason);
+    }
+    if bencode_decoder.warnings().is_empty() {
+        println!("{}: canonical", file_path.display());
+    } else {
+        println!(
+            "{}: {} non-canonical constructs",
+            file_path.display(),
+            bencode_decoder.warnings().len()
+        );
+    }
     Ok(())
 }
 
 fn handle_info_command(file_path: &PathBuf) -> Result<(), crate::Error> {
     let buffer = read_file(file_path)?;
-    let mut bencode_decoder = decoder::Decoder::new(&buffer);
-    let decoded_value = bencode_decoder.decode()?;
-    info_command(decoded_value);
+    let torrent = Torrent::from_bencode(&buffer)?;
+    info_command(&torrent);
     Ok(())
 }
 
-fn handle_peers_command(file_path: &PathBuf) -> Result<(), crate::Error> {
+async fn handle_peers_command(file_path: &PathBuf, dht_args: &DhtArgs) -> Result<(), crate::Error> {
     let buffer = read_file(file_path)?;
-    let mut bencode_decoder = decoder::Decoder::new(&buffer);
-    let decoded_value = bencode_decoder.decode()?;
-    let torrent = Torrent::from_bencode(decoded_value)?;
-    let tracker = tracker::Tracker::new(torrent.info.length as u64);
-    let peers = tracker.get_peers(&torrent.announce, &url_encode(&torrent.info_hash()?))?;
+    let torrent = Torrent::from_bencode(&buffer)?;
+    let tracker = tracker::Tracker::new(torrent.info.length() as u64);
+    let dht = dht_args.start_for(&torrent).await?;
+    let mut trackers = tracker::TrackerList::from_torrent(&torrent);
+    let peers = match &dht {
+        None => trackers.get_peers(&tracker, &torrent.info_hash()).await?,
+        Some(dht) => {
+            let mut peers = Vec::new();
+            if !trackers.is_empty() {
+                match trackers.get_peers(&tracker, &torrent.info_hash()).await {
+                    Ok(tracker_peers) => peers = tracker_peers,
+                    Err(e) => eprintln!("Trackers failed: {}", e),
+                }
+            }
+            add_peers(&mut peers, dht.get_peers(torrent.info_hash()).await);
+            save_dht_state(dht);
+            if peers.is_empty() {
+                return Err(crate::Error::NoPeers);
+            }
+            peers
+        }
+    };
 
     for peer in peers {
         println!("{}:{}", peer.ip(), peer.port());
@@ -98,97 +422,483 @@ fn handle_peers_command(file_path: &PathBuf) -> Result<(), crate::Error> {
     Ok(())
 }
 
-fn handle_handshake_command(
+async fn handle_handshake_command(
     torrent_file: &PathBuf,
     peer_address: &str,
 ) -> Result<(), crate::Error> {
     let buffer = read_file(torrent_file)?;
-    let mut bencode_decoder = decoder::Decoder::new(&buffer);
-    let decoded_value = bencode_decoder.decode()?;
-    let torrent = Torrent::from_bencode(decoded_value)?;
+    let torrent = Torrent::from_bencode(&buffer)?;
 
-    let peer_addr: SocketAddrV4 = peer_address.parse().expect("Invalid peer address");
+    let peer_addr: SocketAddr = peer_address.parse().expect("Invalid peer address");
 
-    let info_hash = torrent.info_hash()?;
+    let info_hash = torrent.info_hash();
     let peer_id = b"00112233445566778899".to_owned();
 
     let mut handshake = handshake::Handshake::new(info_hash, peer_id);
     let handshake_bytes = handshake.as_bytes_mut();
 
-    let mut peer = std::net::TcpStream::connect(peer_addr)?;
+    let mut peer = tokio::net::TcpStream::connect(peer_addr).await?;
 
-    peer.write_all(handshake_bytes).unwrap();
-    peer.read_exact(handshake_bytes).unwrap();
+    peer.write_all(handshake_bytes).await?;
+    peer.read_exact(handshake_bytes).await?;
 
     println!("Peer ID: {}", hex::encode(handshake.peer_id));
 
     Ok(())
 }
 
-fn handle_download_piece_command(
-    output: &PathBuf,
+async fn handle_download_piece_command(
+    output: &Path,
     torrent: &PathBuf,
     piece_index: usize,
 ) -> Result<(), crate::Error> {
     let buffer = read_file(torrent)?;
-    let mut bencode_decoder = decoder::Decoder::new(&buffer);
-    let decoded_value = bencode_decoder.decode()?;
-    let torrent = Torrent::from_bencode(decoded_value)?;
-    let tracker = tracker::Tracker::new(torrent.info.length as u64);
-    let peers = tracker.get_peers(&torrent.announce, &url_encode(&torrent.info_hash()?))?;
-    let info_hash = torrent.info_hash()?;
+    let torrent = Torrent::from_bencode(&buffer)?;
+    let tracker = tracker::Tracker::new(torrent.info.length() as u64);
+    let peers = tracker::TrackerList::from_torrent(&torrent)
+        .get_peers(&tracker, &torrent.info_hash())
+        .await?;
     let peer_id = b"00112233445566778899".to_owned();
-    let mut handshake = handshake::Handshake::new(info_hash, peer_id);
-    let handshake_bytes = handshake.as_bytes_mut();
 
-    let peer_addr: SocketAddrV4 = peers[0];
-    let mut peer = std::net::TcpStream::connect(peer_addr)?;
+    // A peer sending a corrupt piece is skipped, the piece is requested from the next one.
+    let mut piece = Err(crate::Error::NoPeers);
+    for &peer_addr in &peers {
+        piece = fetch_piece(&torrent, peer_addr, peer_id, piece_index).await;
+        match &piece {
+            Ok(_) => break,
+            Err(e) => eprintln!("Peer {} failed: {}", peer_addr, e),
+        }
+    }
+    let piece = piece?;
+
+    let mut storage = storage::FileStorage::create_piece(output, piece.len() as u64)?;
+    storage.write_piece(0, &piece)?;
+
+    Ok(())
+}
 
-    peer.write_all(handshake_bytes)?;
-    peer.read_exact(handshake_bytes)?;
+/// Downloads a piece from one peer and checks its hash.
+async fn fetch_piece(
+    torrent: &Torrent,
+    peer_addr: SocketAddr,
+    peer_id: [u8; 20],
+    piece_index: usize,
+) -> Result<Vec<u8>, crate::Error> {
+    let mut peer_connection =
+        PeerConnection::connect(peer_addr, torrent.info_hash(), peer_id).await?;
+    peer_connection.set_num_pieces(torrent.num_pieces());
+    peer_connection.prepare_download().await?;
+
+    let piece = peer_connection
+        .download_piece(piece_index, torrent.piece_size(piece_index))
+        .await?;
+    download::verify_piece(torrent, piece_index, &piece)?;
+    Ok(piece)
+}
 
-    let mut peer_connection = PeerConnection::new(peer);
-    peer_connection.expect_message(PeerMessageType::Bitfield)?;
+async fn handle_download_command(
+    output: &Path,
+    torrent: &PathBuf,
+    options: download::DownloadOptions,
+    dht_args: &DhtArgs,
+    seed: bool,
+) -> Result<(), crate::Error> {
+    let buffer = read_file(torrent)?;
+    let torrent = Torrent::from_bencode(&buffer)?;
+    let peer_id = b"00112233445566778899".to_owned();
+    let progress = download::Progress::new(&torrent);
+    // Opened first so the announces report what a resumed download still misses.
+    let download = download::Download::open(&torrent, output, options, &progress)?;
+    let shared = download.shared();
+    let transfer = || tracker::Transfer {
+        uploaded: shared.uploaded.load(Ordering::Relaxed),
+        downloaded: progress.downloaded.load(Ordering::Relaxed),
+        left: progress.left.load(Ordering::Relaxed),
+    };
 
-    peer_connection.send_message(PeerMessageType::Interested, &[])?;
-    peer_connection.expect_message(PeerMessageType::Unchoke)?;
+    let dht = dht_args.start_for(&torrent).await?;
+    let mut announcer = tracker::Announcer::new(&torrent, tracker::DEFAULT_PORT);
+    let mut peers = match announcer
+        .announce(Some(tracker::Event::Started), transfer())
+        .await
+    {
+        Ok(started) => started.peers,
+        Err(e) if dht.is_some() => {
+            eprintln!("Announce failed: {}", e);
+            Vec::new()
+        }
+        Err(e) => return Err(e),
+    };
+    // Nothing listens while downloading, so the DHT only hears about us once seeding.
+    if let Some(dht) = &dht {
+        add_peers(&mut peers, dht.get_peers(torrent.info_hash()).await);
+        save_dht_state(dht);
+    }
+    if peers.is_empty() {
+        return Err(crate::Error::NoPeers);
+    }
 
-    let num_pieces = torrent.num_pieces();
-    let piece_size = if piece_index == (num_pieces - 1) as usize {
-        if torrent.info.length % torrent.info.piece_length == 0 {
-            torrent.info.piece_length
-        } else {
-            torrent.info.length % torrent.info.piece_length
+    // Keep announcing while downloading, so the tracker doesn't forget about us, and hand
+    // the peers it returns to the download.
+    let result = {
+        let (more_peers, more_peers_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let download = download.run(&peers, more_peers_receiver, peer_id, &progress);
+        tokio::pin!(download);
+        loop {
+            tokio::select! {
+                result = &mut download => break result,
+                _ = tokio::time::sleep(announcer.until_next()) => {
+                    if let Some(response) = announce_event(&mut announcer, None, transfer()).await {
+                        let _ = more_peers.send(response.peers);
+                    }
+                }
+            }
         }
+    };
+
+    if let Err(e) = result {
+        announce_event(&mut announcer, Some(tracker::Event::Stopped), transfer()).await;
+        return Err(e);
+    }
+    println!("Downloaded {} to {}.", torrent.info.name, output.display());
+    // A download that found everything on disk has no completion to report.
+    let downloaded = progress.downloaded.load(Ordering::Relaxed);
+    if downloaded > 0 {
+        announce_event(&mut announcer, Some(tracker::Event::Completed), transfer()).await;
+    }
+
+    if seed {
+        seed_torrent(shared, options.choker_config, announcer, dht, downloaded).await
     } else {
-        torrent.info.piece_length
+        announce_event(&mut announcer, Some(tracker::Event::Stopped), transfer()).await;
+        Ok(())
+    }
+}
+
+/// Adds peers not already known.
+fn add_peers(peers: &mut Vec<SocketAddr>, more: Vec<SocketAddr>) {
+    for peer in more {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+}
+
+/// Saves the DHT routing table, reporting a failure rather than giving up.
+fn save_dht_state(dht: &dht::Dht) {
+    if let Err(e) = dht.save() {
+        eprintln!("Saving the DHT state failed: {}", e);
+    }
+}
+
+/// Announces to the trackers, reporting a failure rather than giving up.
+async fn announce_event(
+    announcer: &mut tracker::Announcer,
+    event: Option<tracker::Event>,
+    transfer: tracker::Transfer,
+) -> Option<tracker::AnnounceResponse> {
+    announcer
+        .announce(event, transfer)
+        .await
+        .map_err(|e| eprintln!("Announce failed: {}", e))
+        .ok()
+}
+
+fn handle_recheck_command(torrent: &PathBuf, path: &Path) -> Result<(), crate::Error> {
+    let buffer = read_file(torrent)?;
+    let torrent = Torrent::from_bencode(&buffer)?;
+    let mut storage = storage::FileStorage::open(&torrent, path)?;
+    let have = resume::recheck(&torrent, &mut storage);
+
+    println!("Valid: {}", piece_ranges(&have, true));
+    println!("Missing: {}", piece_ranges(&have, false));
+    println!(
+        "{}/{} pieces valid",
+        have.iter().filter(|&&valid| valid).count(),
+        have.len()
+    );
+    // Refresh the state so a following download resumes without hashing again.
+    resume::save(&torrent, path, &have)
+}
+
+/// Formats the pieces whose flag equals `wanted` as ranges, like `0-3, 7, 9-12`.
+fn piece_ranges(flags: &[bool], wanted: bool) -> String {
+    let mut ranges = Vec::new();
+    let mut piece_index = 0;
+    while piece_index < flags.len() {
+        if flags[piece_index] != wanted {
+            piece_index += 1;
+            continue;
+        }
+        let start = piece_index;
+        while piece_index < flags.len() && flags[piece_index] == wanted {
+            piece_index += 1;
+        }
+        ranges.push(match piece_index - 1 - start {
+            0 => start.to_string(),
+            _ => format!("{}-{}", start, piece_index - 1),
+        });
+    }
+    if ranges.is_empty() {
+        "none".to_owned()
+    } else {
+        ranges.join(", ")
+    }
+}
+
+async fn handle_scrape_command(paths: &[PathBuf]) -> Result<(), crate::Error> {
+    let torrents = paths
+        .iter()
+        .map(|path| Torrent::from_bencode(&read_file(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Torrents on the same trackers are scraped together, in one request.
+    let mut groups: Vec<(Vec<Vec<String>>, Vec<usize>)> = Vec::new();
+    for (i, torrent) in torrents.iter().enumerate() {
+        let tiers = tracker::torrent_tiers(torrent);
+        match groups
+            .iter_mut()
+            .find(|(group_tiers, _)| *group_tiers == tiers)
+        {
+            Some((_, indices)) => indices.push(i),
+            None => groups.push((tiers, vec![i])),
+        }
+    }
+
+    let mut results = vec![None; torrents.len()];
+    for (tiers, indices) in groups {
+        let info_hashes: Vec<[u8; 20]> = indices.iter().map(|&i| torrents[i].info_hash()).collect();
+        let stats = tracker::TrackerList::new(tiers).scrape(&info_hashes).await;
+        for (n, &i) in indices.iter().enumerate() {
+            results[i] = Some(match &stats {
+                Ok(stats) => Ok(stats[n]),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+    }
+
+    for (torrent, result) in torrents.iter().zip(results.into_iter().flatten()) {
+        match result {
+            Ok(stats) => println!(
+                "{}: complete {}, incomplete {}, downloaded {}",
+                torrent.info.name, stats.complete, stats.incomplete, stats.downloaded
+            ),
+            Err(e) => println!("{}: {}", torrent.info.name, e),
+        }
+    }
+    Ok(())
+}
+
+async fn handle_seed_command(
+    torrent: &PathBuf,
+    path: &Path,
+    port: u16,
+    choker_config: choke::ChokerConfig,
+) -> Result<(), crate::Error> {
+    let buffer = read_file(torrent)?;
+    let torrent = Torrent::from_bencode(&buffer)?;
+    let announcer = tracker::Announcer::new(&torrent, port);
+    let storage = storage::FileStorage::open(&torrent, path)?;
+    let shared = Arc::new(seed::SharedTorrent::verify(torrent, storage)?);
+    seed_torrent(shared, choker_config, announcer, None, 0).await
+}
+
+/// Serves the torrent's local data and keeps announcing to the trackers until interrupted.
+/// `downloaded` is what this session downloaded before it started seeding.
+async fn seed_torrent(
+    shared: Arc<seed::SharedTorrent>,
+    choker_config: choke::ChokerConfig,
+    mut announcer: tracker::Announcer,
+    dht: Option<Arc<dht::Dht>>,
+    downloaded: u64,
+) -> Result<(), crate::Error> {
+    let port = announcer.port();
+    println!(
+        "Seeding {} ({}/{} pieces verified) on port {}",
+        shared.torrent.info.name,
+        shared.num_verified(),
+        shared.torrent.num_pieces(),
+        port
+    );
+
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    let listening_port = listener.local_addr()?.port();
+    let mut seeder =
+        seed::Seeder::new(b"00112233445566778899".to_owned()).with_choker_config(choker_config);
+    seeder.add(Arc::clone(&shared));
+    let listening = tokio::spawn(Arc::new(seeder).listen(listener));
+
+    let transfer = || tracker::Transfer {
+        uploaded: shared.uploaded.load(Ordering::Relaxed),
+        downloaded,
+        left: shared.left(),
     };
+    if !announcer.has_announced() {
+        announce_event(&mut announcer, Some(tracker::Event::Started), transfer()).await;
+    }
+    let info_hash = shared.torrent.info_hash();
+    let mut dht_rounds = tokio::time::interval(dht::ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = dht_rounds.tick(), if dht.is_some() => {
+                let dht = dht.as_ref().expect("checked by the guard");
+                dht.announce(info_hash, listening_port).await;
+                save_dht_state(dht);
+            }
+            _ = tokio::time::sleep(announcer.until_next()) => {
+                announce_event(&mut announcer, None, transfer()).await;
+            }
+        }
+    }
 
-    const BLOCK_SIZE: u32 = 2u32.pow(14);
+    listening.abort();
+    announce_event(&mut announcer, Some(tracker::Event::Stopped), transfer()).await;
+    println!("Uploaded {} bytes", shared.uploaded.load(Ordering::Relaxed));
+    Ok(())
+}
 
-    let mut piece = vec![0u8; piece_size as usize];
+async fn handle_tracker_serve_command(
+    port: u16,
+    interval: u64,
+    allow: &[PathBuf],
+) -> Result<(), crate::Error> {
+    let whitelist = if allow.is_empty() {
+        None
+    } else {
+        let info_hashes = allow
+            .iter()
+            .map(|path| Ok(Torrent::from_bencode(&read_file(path)?)?.info_hash()))
+            .collect::<Result<_, crate::Error>>()?;
+        Some(info_hashes)
+    };
+    let interval = Duration::from_secs(interval);
+    let config = tracker_server::TrackerServerConfig {
+        interval,
+        // Clients get one missed announce of slack before they are dropped.
+        peer_timeout: interval * 2,
+        whitelist,
+        ..Default::default()
+    };
 
-    for (i, chunk) in piece.chunks_mut(BLOCK_SIZE as usize).enumerate() {
-        let request_payload = RequestPayload::new(
-            piece_index as u32,
-            i as u32 * BLOCK_SIZE,
-            chunk.len() as u32,
-        );
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    println!("Tracker listening on port {}", port);
+    let server = Arc::new(tracker_server::TrackerServer::new(config));
+    tokio::select! {
+        result = server.listen(listener) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
 
-        peer_connection.send_message(PeerMessageType::Request, &request_payload.as_bytes())?;
-        let peer_message = peer_connection.expect_message(PeerMessageType::Piece)?;
+fn handle_magnet_parse_command(magnet_link: &str) -> Result<(), crate::Error> {
+    let magnet = magnet::MagnetLink::parse(magnet_link)?;
+    for tracker in &magnet.trackers {
+        println!("Tracker URL: {}", tracker);
+    }
+    println!("Info Hash: {}", hex::encode(magnet.info_hash));
+    if let Some(name) = &magnet.name {
+        println!("Name: {}", name);
+    }
+    for peer in &magnet.peers {
+        println!("Peer: {}", peer);
+    }
+    Ok(())
+}
+
+async fn handle_magnet_info_command(
+    magnet_link: &str,
+    dht_args: &DhtArgs,
+) -> Result<(), crate::Error> {
+    let magnet = magnet::MagnetLink::parse(magnet_link)?;
+    let peers = magnet_peers(&magnet, dht_args).await?;
+    let torrent = fetch_magnet_torrent(&magnet, &peers).await?;
+    info_command(&torrent);
+    Ok(())
+}
+
+async fn handle_magnet_download_command(
+    output: &Path,
+    magnet_link: &str,
+    options: download::DownloadOptions,
+    dht_args: &DhtArgs,
+) -> Result<(), crate::Error> {
+    let magnet = magnet::MagnetLink::parse(magnet_link)?;
+    let peers = magnet_peers(&magnet, dht_args).await?;
+    let torrent = fetch_magnet_torrent(&magnet, &peers).await?;
+    let peer_id = b"00112233445566778899".to_owned();
 
-        let piece_payload = PiecePayload::from(peer_message.payload.as_slice());
-        chunk.copy_from_slice(&piece_payload.block);
+    let progress = download::Progress::new(&torrent);
+    download::download(&torrent, &peers, peer_id, output, options, &progress).await?;
+    println!("Downloaded {} to {}.", torrent.info.name, output.display());
+
+    Ok(())
+}
+
+/// Peers for a magnet link: the ones listed in the link plus those its trackers and the
+/// DHT know.
+async fn magnet_peers(
+    magnet: &magnet::MagnetLink,
+    dht_args: &DhtArgs,
+) -> Result<Vec<SocketAddr>, crate::Error> {
+    let mut peers = magnet.peers.clone();
+    let has_sources = !magnet.trackers.is_empty() || !magnet.peers.is_empty();
+    let dht = dht_args.start(has_sources, &[]).await?;
+    if !magnet.trackers.is_empty() {
+        // The length is unknown until the metadata arrives; any non-zero `left` will do.
+        let tracker = tracker::Tracker::new(999);
+        let mut trackers = tracker::TrackerList::new(vec![magnet.trackers.clone()]);
+        // Peers from the link itself or the DHT may still work when the trackers don't.
+        match trackers.get_peers(&tracker, &magnet.info_hash).await {
+            Ok(tracker_peers) => add_peers(&mut peers, tracker_peers),
+            Err(e) => eprintln!("Trackers failed: {}", e),
+        }
+    }
+    if let Some(dht) = &dht {
+        add_peers(&mut peers, dht.get_peers(magnet.info_hash).await);
+        save_dht_state(dht);
     }
 
-    let piece_hashes = torrent.piece_hashes();
-    let piece_hash = &piece_hashes[piece_index];
-    assert_eq!(*piece_hash, hex::encode(sha1::Sha1::digest(&piece)));
+    if peers.is_empty() {
+        return Err(crate::Error::NoPeers);
+    }
+    Ok(peers)
+}
 
-    let mut file = fs::File::create(output)?;
-    file.write_all(&piece)?;
+/// Fetches the info dict from the first peer able to provide it.
+async fn fetch_magnet_torrent(
+    magnet: &magnet::MagnetLink,
+    peers: &[SocketAddr],
+) -> Result<Torrent, crate::Error> {
+    let peer_id = b"00112233445566778899".to_owned();
+    let announce = magnet.trackers.first().cloned().unwrap_or_default();
+
+    let mut last_error = crate::Error::NoPeers;
+    for &peer_addr in peers {
+        let metadata = async {
+            let mut peer =
+                PeerConnection::connect_with_extensions(peer_addr, magnet.info_hash, peer_id)
+                    .await?;
+            peer.read_bitfield().await?;
+            peer.fetch_metadata(magnet.info_hash).await
+        };
+        match metadata.await {
+            Ok(metadata) => return Torrent::from_info_bytes(announce, &metadata),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
 
+fn handle_create_command(
+    output: &Path,
+    path: &Path,
+    options: &create::CreateOptions,
+) -> Result<(), crate::Error> {
+    let torrent = create::create_torrent(path, options)?;
+    fs::write(output, torrent.to_bencode()?)?;
+
+    println!("Created {}", output.display());
+    println!("Info Hash: {}", torrent.info_hash_hex_string());
     Ok(())
 }
 
@@ -199,34 +909,19 @@ fn read_file(path: &PathBuf) -> Result<Vec<u8>, crate::Error> {
     Ok(buffer)
 }
 
-fn info_command(decoded_value: serde_json::Value) {
-    let torrent = Torrent::from_bencode(decoded_value).expect("Failed to parse torrent");
+fn info_command(torrent: &Torrent) {
     println!("Tracker URL: {}", torrent.announce);
-    println!("Length: {}", torrent.info.length);
-    println!(
-        "Info Hash: {}",
-        torrent
-            .info_hash_hex_string()
-            .expect("Failed to convert info hash to hex string")
-    );
+    println!("Length: {}", torrent.info.length());
+    if torrent.info.files.is_some() {
+        println!("Files:");
+        for (path, length) in torrent.files() {
+            println!("{} ({} bytes)", path.display(), length);
+        }
+    }
+    println!("Info Hash: {}", torrent.info_hash_hex_string());
     println!("Piece Length: {}", torrent.info.piece_length);
     println!("Piece Hashes:");
     for hash in torrent.piece_hashes() {
         println!("{}", hash);
     }
 }
-
-fn url_encode(input: &[u8; 20]) -> String {
-    let unreserved_characters =
-        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
-    input
-        .iter()
-        .flat_map(|&byte| {
-            if unreserved_characters.contains(&byte) {
-                vec![byte as char]
-            } else {
-                format!("%{:02x}", byte).chars().collect()
-            }
-        })
-        .collect()
-}
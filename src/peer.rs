@@ -1,28 +1,175 @@
-use std::{
-    io::{Read, Write},
+use std::{collections::VecDeque, future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    time::timeout,
 };
 
-use crate::Error;
+use crate::{choke::ChokeSlot, handshake::Handshake, Error};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const BLOCK_SIZE: u32 = 2u32.pow(14);
+/// Largest message we accept. Leaves room for the bitfield of very large torrents.
+const MAX_MESSAGE_LENGTH: u32 = 1024 * 1024;
+/// Outstanding block requests per peer unless configured otherwise.
+pub(crate) const DEFAULT_PIPELINE_DEPTH: usize = 5;
+/// Messages queued while waiting for another type, beyond which the peer is dropped.
+const MAX_PENDING_MESSAGES: usize = 256;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(crate) enum PeerMessageType {
+    Choke = 0,
     Unchoke = 1,
     Interested = 2,
+    NotInterested = 3,
+    Have = 4,
     Bitfield = 5,
     Request = 6,
     Piece = 7,
+    Cancel = 8,
+    Port = 9,
+    Extended = 20,
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub(crate) struct PeerMessage {
-    pub(crate) length: u32,
-    pub(crate) id: PeerMessageType,
-    pub(crate) payload: Vec<u8>,
+impl TryFrom<u8> for PeerMessageType {
+    type Error = Error;
+
+    fn try_from(id: u8) -> Result<Self, Error> {
+        Ok(match id {
+            0 => PeerMessageType::Choke,
+            1 => PeerMessageType::Unchoke,
+            2 => PeerMessageType::Interested,
+            3 => PeerMessageType::NotInterested,
+            4 => PeerMessageType::Have,
+            5 => PeerMessageType::Bitfield,
+            6 => PeerMessageType::Request,
+            7 => PeerMessageType::Piece,
+            8 => PeerMessageType::Cancel,
+            9 => PeerMessageType::Port,
+            20 => PeerMessageType::Extended,
+            id => return Err(Error::InvalidMessageType(id)),
+        })
+    }
+}
+
+/// A peer wire protocol message (BEP 3), plus the extension protocol message (BEP 10).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum PeerMessage {
+    /// Zero-length message sent to keep an idle connection open.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(RequestPayload),
+    Piece(PiecePayload),
+    Cancel(RequestPayload),
+    /// DHT listen port of the peer.
+    Port(u16),
+    /// Extension id and its payload.
+    Extended(u8, Vec<u8>),
+}
+
+impl PeerMessage {
+    pub(crate) fn message_type(&self) -> Option<PeerMessageType> {
+        Some(match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => PeerMessageType::Choke,
+            PeerMessage::Unchoke => PeerMessageType::Unchoke,
+            PeerMessage::Interested => PeerMessageType::Interested,
+            PeerMessage::NotInterested => PeerMessageType::NotInterested,
+            PeerMessage::Have(_) => PeerMessageType::Have,
+            PeerMessage::Bitfield(_) => PeerMessageType::Bitfield,
+            PeerMessage::Request(_) => PeerMessageType::Request,
+            PeerMessage::Piece(_) => PeerMessageType::Piece,
+            PeerMessage::Cancel(_) => PeerMessageType::Cancel,
+            PeerMessage::Port(_) => PeerMessageType::Port,
+            PeerMessage::Extended(..) => PeerMessageType::Extended,
+        })
+    }
+
+    /// Encodes the message including its 4-byte length prefix.
+    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            PeerMessage::Have(index) => payload.extend_from_slice(&index.to_be_bytes()),
+            PeerMessage::Bitfield(bitfield) => payload.extend_from_slice(bitfield),
+            PeerMessage::Request(request) | PeerMessage::Cancel(request) => {
+                payload.extend(request.as_bytes())
+            }
+            PeerMessage::Piece(piece) => {
+                payload.extend_from_slice(&piece.index.to_be_bytes());
+                payload.extend_from_slice(&piece.begin.to_be_bytes());
+                payload.extend_from_slice(&piece.block);
+            }
+            PeerMessage::Port(port) => payload.extend_from_slice(&port.to_be_bytes()),
+            PeerMessage::Extended(extension_id, extension_payload) => {
+                payload.push(*extension_id);
+                payload.extend_from_slice(extension_payload);
+            }
+            _ => {}
+        }
+
+        let mut buffer = Vec::with_capacity(5 + payload.len());
+        match self.message_type() {
+            Some(message_type) => {
+                buffer.extend_from_slice(&(1 + payload.len() as u32).to_be_bytes());
+                buffer.push(message_type as u8);
+                buffer.extend(payload);
+            }
+            None => buffer.extend_from_slice(&0u32.to_be_bytes()),
+        }
+        buffer
+    }
+
+    /// Decodes a message body, i.e. everything after the length prefix.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let Some((&id, payload)) = bytes.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+
+        let expect_len = |len: usize| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(Error::InvalidMessagePayload(id))
+            }
+        };
+
+        Ok(match PeerMessageType::try_from(id)? {
+            PeerMessageType::Choke => expect_len(0).map(|_| PeerMessage::Choke)?,
+            PeerMessageType::Unchoke => expect_len(0).map(|_| PeerMessage::Unchoke)?,
+            PeerMessageType::Interested => expect_len(0).map(|_| PeerMessage::Interested)?,
+            PeerMessageType::NotInterested => expect_len(0).map(|_| PeerMessage::NotInterested)?,
+            PeerMessageType::Have => {
+                expect_len(4)?;
+                PeerMessage::Have(u32::from_be_bytes(payload.try_into().unwrap()))
+            }
+            PeerMessageType::Bitfield => PeerMessage::Bitfield(payload.to_vec()),
+            PeerMessageType::Request => PeerMessage::Request(RequestPayload::try_from(payload)?),
+            PeerMessageType::Piece => PeerMessage::Piece(PiecePayload::try_from(payload)?),
+            PeerMessageType::Cancel => PeerMessage::Cancel(RequestPayload::try_from(payload)?),
+            PeerMessageType::Port => {
+                expect_len(2)?;
+                PeerMessage::Port(u16::from_be_bytes(payload.try_into().unwrap()))
+            }
+            PeerMessageType::Extended => match payload.split_first() {
+                Some((&extension_id, extension_payload)) => {
+                    PeerMessage::Extended(extension_id, extension_payload.to_vec())
+                }
+                None => return Err(Error::InvalidMessagePayload(id)),
+            },
+        })
+    }
 }
 
 #[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(crate) struct RequestPayload {
     pub(crate) index: u32,
     pub(crate) begin: u32,
@@ -30,23 +177,43 @@ pub(crate) struct RequestPayload {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub(crate) struct PiecePayload {
     pub(crate) index: u32,
     pub(crate) begin: u32,
     pub(crate) block: Vec<u8>,
 }
 
-impl From<&[u8]> for PiecePayload {
-    fn from(bytes: &[u8]) -> Self {
+impl TryFrom<&[u8]> for PiecePayload {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 8 {
+            return Err(Error::InvalidMessagePayload(PeerMessageType::Piece as u8));
+        }
         let index = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
         let begin = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
         let block = bytes[8..].to_vec();
-        Self {
+        Ok(Self {
             index,
             begin,
             block,
+        })
+    }
+}
+
+impl TryFrom<&[u8]> for RequestPayload {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != 12 {
+            return Err(Error::InvalidMessagePayload(PeerMessageType::Request as u8));
         }
+        Ok(Self::new(
+            u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+        ))
     }
 }
 
@@ -68,71 +235,724 @@ impl RequestPayload {
     }
 }
 
+/// Splits a byte stream into length-prefixed peer messages.
+#[derive(Debug, Default)]
+pub(crate) struct PeerCodec;
+
+impl PeerCodec {
+    /// Decodes one message from the front of `src`, or returns `None` if it is incomplete.
+    pub(crate) fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PeerMessage>, Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(src[..4].try_into().unwrap());
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(Error::MessageTooLarge(length));
+        }
+
+        let frame_length = 4 + length as usize;
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(frame_length);
+        PeerMessage::from_bytes(&frame[4..]).map(Some)
+    }
+
+    pub(crate) fn encode(&mut self, message: &PeerMessage, dst: &mut BytesMut) {
+        dst.extend_from_slice(&message.as_bytes());
+    }
+}
+
+/// Local data a connection answers requests from.
+pub(crate) trait BlockSource: Send + Sync {
+    /// Reads the requested block, or returns `None` if it is not one we can serve.
+    fn read_block(&self, request: &RequestPayload) -> Result<Option<Vec<u8>>, Error>;
+}
+
 pub struct PeerConnection {
     stream: TcpStream,
+    codec: PeerCodec,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+    bitfield: Vec<u8>,
+    /// Piece count of the torrent, once known. Availability beyond it is ignored.
+    num_pieces: Option<usize>,
+    /// Whether the remote peer set the extension protocol bit in its handshake.
+    pub(crate) supports_extensions: bool,
+    /// Whether the remote peer is currently choking us.
+    pub(crate) peer_choking: bool,
+    /// Whether the remote peer is interested in our pieces.
+    pub(crate) peer_interested: bool,
+    /// Whether we are choking the remote peer.
+    pub(crate) am_choking: bool,
+    /// Number of block requests kept outstanding while downloading a piece.
+    pub(crate) pipeline_depth: usize,
+    /// Messages read while waiting for a different one, returned by later reads.
+    pending: VecDeque<PeerMessage>,
+    /// What a download connection uploads from, and its place under the choker.
+    uploads: Option<(Arc<dyn BlockSource>, ChokeSlot)>,
 }
 
 impl PeerConnection {
     pub fn new(stream: TcpStream) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            codec: PeerCodec,
+            read_buffer: BytesMut::with_capacity(BLOCK_SIZE as usize + 13),
+            write_buffer: BytesMut::new(),
+            bitfield: Vec::new(),
+            num_pieces: None,
+            supports_extensions: false,
+            peer_choking: true,
+            peer_interested: false,
+            am_choking: true,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            pending: VecDeque::new(),
+            uploads: None,
+        }
     }
 
-    pub fn send_message(
-        &mut self,
-        message_type: PeerMessageType,
-        payload: &[u8],
-    ) -> Result<(), Error> {
-        let length = 1 + payload.len() as u32;
+    pub async fn connect(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> Result<Self, Error> {
+        Self::open(peer_addr, Handshake::new(info_hash, peer_id)).await
+    }
 
-        self.stream.write_all(&length.to_be_bytes())?;
+    /// Connects with the extension protocol (BEP 10) enabled in our handshake.
+    pub async fn connect_with_extensions(
+        peer_addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> Result<Self, Error> {
+        Self::open(
+            peer_addr,
+            Handshake::new(info_hash, peer_id).with_extensions(),
+        )
+        .await
+    }
+
+    async fn open(peer_addr: SocketAddr, mut handshake: Handshake) -> Result<Self, Error> {
+        let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(peer_addr))
+            .await
+            .map_err(io::Error::from)??;
 
-        let message_id = message_type as u8;
-        self.stream.write_all(&[message_id])?;
+        let handshake_bytes = handshake.as_bytes_mut();
+        stream.write_all(handshake_bytes).await?;
+        timeout(READ_TIMEOUT, stream.read_exact(handshake_bytes))
+            .await
+            .map_err(io::Error::from)??;
 
-        if !payload.is_empty() {
-            self.stream.write_all(payload)?;
+        let mut connection = Self::new(stream);
+        connection.supports_extensions = handshake.supports_extensions();
+        Ok(connection)
+    }
+
+    /// Completes the handshake of an incoming connection. The connection is refused unless
+    /// `serves` accepts the requested info hash, which is returned with the connection.
+    pub async fn accept(
+        mut stream: TcpStream,
+        peer_id: [u8; 20],
+        serves: impl Fn(&[u8; 20]) -> bool,
+    ) -> Result<(Self, [u8; 20]), Error> {
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        timeout(READ_TIMEOUT, stream.read_exact(handshake.as_bytes_mut()))
+            .await
+            .map_err(io::Error::from)??;
+        if handshake.length != 19 || &handshake.bittorrent != b"BitTorrent protocol" {
+            return Err(Error::InvalidHandshake);
+        }
+
+        let info_hash = handshake.info_hash;
+        if !serves(&info_hash) {
+            return Err(Error::UnknownInfoHash(hex::encode(info_hash)));
         }
 
+        let supports_extensions = handshake.supports_extensions();
+        let mut reply = Handshake::new(info_hash, peer_id);
+        stream.write_all(reply.as_bytes_mut()).await?;
+
+        let mut connection = Self::new(stream);
+        connection.supports_extensions = supports_extensions;
+        Ok((connection, info_hash))
+    }
+
+    /// Lets the connection upload while it downloads: requests are answered from `source`
+    /// once the choker unchokes the peer, and the transfers are reported to it.
+    pub(crate) fn upload_from(&mut self, source: Arc<dyn BlockSource>, slot: ChokeSlot) {
+        self.uploads = Some((source, slot));
+    }
+
+    /// Declares interest and waits to be unchoked. The bitfield is optional, so availability
+    /// is taken from whatever `Bitfield` or `Have` messages arrive in the meantime.
+    pub async fn prepare_download(&mut self) -> Result<(), Error> {
+        self.request_unchoke().await
+    }
+
+    pub async fn read_bitfield(&mut self) -> Result<(), Error> {
+        self.expect_message(PeerMessageType::Bitfield).await?;
         Ok(())
     }
 
-    pub fn read_message(&mut self) -> Result<PeerMessage, Error> {
-        let mut length_buf = [0u8; 4];
-        self.stream.read_exact(&mut length_buf)?;
-        let length = u32::from_be_bytes(length_buf);
+    pub async fn request_unchoke(&mut self) -> Result<(), Error> {
+        self.send(&PeerMessage::Interested).await?;
+        if self.peer_choking {
+            self.expect_message(PeerMessageType::Unchoke).await?;
+        }
+        Ok(())
+    }
 
-        let mut message_buf = vec![0u8; length as usize];
-        self.stream.read_exact(&mut message_buf)?;
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        if self
+            .num_pieces
+            .is_some_and(|num_pieces| piece_index >= num_pieces)
+        {
+            return false;
+        }
+        let byte = piece_index / 8;
+        let bit = 7 - (piece_index % 8);
+        self.bitfield
+            .get(byte)
+            .is_some_and(|&b| (b >> bit) & 1 == 1)
+    }
 
-        let message_type = match message_buf[0] {
-            1 => PeerMessageType::Unchoke,
-            5 => PeerMessageType::Bitfield,
-            6 => PeerMessageType::Request,
-            7 => PeerMessageType::Piece,
-            id => return Err(Error::InvalidMessageType(id)),
+    /// Sizes the peer's bitfield for a torrent of `num_pieces`. Until this is called, `Have`
+    /// messages can only mark pieces inside a bitfield the peer sent.
+    pub(crate) fn set_num_pieces(&mut self, num_pieces: usize) {
+        self.num_pieces = Some(num_pieces);
+        self.bitfield.resize(num_pieces.div_ceil(8), 0);
+    }
+
+    /// Marks a piece as available. The bitfield never grows from a peer's message, so an
+    /// index past the torrent can't make us allocate.
+    fn set_piece(&mut self, piece_index: usize) {
+        if self
+            .num_pieces
+            .is_some_and(|num_pieces| piece_index >= num_pieces)
+        {
+            return;
+        }
+        if let Some(byte) = self.bitfield.get_mut(piece_index / 8) {
+            *byte |= 0x80 >> (piece_index % 8);
+        }
+    }
+
+    /// Downloads a piece block by block, keeping up to `pipeline_depth` requests in flight.
+    /// Blocks may arrive in any order.
+    pub async fn download_piece(
+        &mut self,
+        piece_index: usize,
+        piece_size: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let piece = self
+            .download_piece_until(piece_index, piece_size, std::future::pending())
+            .await?;
+        Ok(piece.expect("a download without cancellation runs to completion"))
+    }
+
+    /// Like `download_piece`, but gives up once `cancelled` resolves, sending `Cancel` for
+    /// every block still outstanding. Returns `None` if the download was cancelled.
+    pub async fn download_piece_until(
+        &mut self,
+        piece_index: usize,
+        piece_size: usize,
+        cancelled: impl Future<Output = ()>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        tokio::pin!(cancelled);
+        let mut piece = vec![0u8; piece_size];
+        let num_blocks = piece_size.div_ceil(BLOCK_SIZE as usize);
+        let mut received = vec![false; num_blocks];
+        let mut requested = 0;
+        let mut completed = 0;
+
+        let block_request = |block_index: usize| {
+            let begin = block_index * BLOCK_SIZE as usize;
+            let length = (piece_size - begin).min(BLOCK_SIZE as usize);
+            RequestPayload::new(piece_index as u32, begin as u32, length as u32)
         };
 
-        let payload = if message_buf.len() > 1 {
-            message_buf[1..].to_vec()
-        } else {
-            Vec::new()
+        while completed < num_blocks {
+            while requested < num_blocks && requested - completed < self.pipeline_depth.max(1) {
+                let request = PeerMessage::Request(block_request(requested));
+                self.codec.encode(&request, &mut self.write_buffer);
+                requested += 1;
+            }
+            self.flush().await?;
+
+            let message = tokio::select! {
+                message = self.expect_message(PeerMessageType::Piece) => message?,
+                _ = &mut cancelled => {
+                    for block_index in (0..requested).filter(|&i| !received[i]) {
+                        let cancel = PeerMessage::Cancel(block_request(block_index));
+                        self.codec.encode(&cancel, &mut self.write_buffer);
+                    }
+                    self.flush().await?;
+                    return Ok(None);
+                }
+            };
+            let block = match message {
+                PeerMessage::Piece(block) => block,
+                message => return Err(unexpected_message(PeerMessageType::Piece, &message)),
+            };
+
+            // A late answer to a request we cancelled for another piece.
+            if block.index as usize != piece_index {
+                continue;
+            }
+
+            let block_index = block.begin as usize / BLOCK_SIZE as usize;
+            let begin = block_index * BLOCK_SIZE as usize;
+            if block.begin as usize != begin
+                || block_index >= requested
+                || received[block_index]
+                || block.block.len() != (piece_size - begin).min(BLOCK_SIZE as usize)
+            {
+                return Err(Error::InvalidMessagePayload(PeerMessageType::Piece as u8));
+            }
+            piece[begin..begin + block.block.len()].copy_from_slice(&block.block);
+            received[block_index] = true;
+            if let Some((_, slot)) = &self.uploads {
+                slot.add_downloaded(block.block.len() as u64);
+            }
+            completed += 1;
+        }
+
+        Ok(Some(piece))
+    }
+
+    pub async fn choke(&mut self) -> Result<(), Error> {
+        self.am_choking = true;
+        self.send(&PeerMessage::Choke).await
+    }
+
+    pub async fn unchoke(&mut self) -> Result<(), Error> {
+        self.am_choking = false;
+        self.send(&PeerMessage::Unchoke).await
+    }
+
+    pub async fn send(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        self.codec.encode(message, &mut self.write_buffer);
+        self.flush().await
+    }
+
+    /// Writes out everything encoded so far. Safe to cancel: what was not written yet goes
+    /// out with the next flush.
+    async fn flush(&mut self) -> Result<(), Error> {
+        while !self.write_buffer.is_empty() {
+            let written = self.stream.write(&self.write_buffer).await?;
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            self.write_buffer.advance(written);
+        }
+        Ok(())
+    }
+
+    /// Answers a request from the peer we unchoked, out of the local data.
+    async fn serve_request(&mut self, request: RequestPayload) -> Result<(), Error> {
+        let Some((source, _)) = &self.uploads else {
+            return Ok(());
         };
+        let block = source
+            .read_block(&request)?
+            .ok_or(Error::InvalidMessagePayload(PeerMessageType::Request as u8))?;
+        let piece = PiecePayload {
+            index: request.index,
+            begin: request.begin,
+            block,
+        };
+        self.send(&PeerMessage::Piece(piece)).await?;
+        if let Some((_, slot)) = &self.uploads {
+            slot.add_uploaded(request.length as u64);
+        }
+        Ok(())
+    }
 
-        Ok(PeerMessage {
-            length,
-            id: message_type,
-            payload,
-        })
+    /// Chokes or unchokes the peer as the choker decided.
+    async fn apply_decision(&mut self, choke: bool) -> Result<(), Error> {
+        match (choke, self.am_choking) {
+            (true, false) => self.choke().await,
+            (false, true) => self.unchoke().await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the next message, including keep-alives.
+    pub async fn read_message(&mut self) -> Result<PeerMessage, Error> {
+        match self.pending.pop_front() {
+            Some(message) => Ok(message),
+            None => self.read_frame().await,
+        }
+    }
+
+    /// Reads the next message off the wire, bypassing queued messages.
+    async fn read_frame(&mut self) -> Result<PeerMessage, Error> {
+        read_frame(&mut self.stream, &mut self.codec, &mut self.read_buffer).await
+    }
+
+    /// Like `read_frame`, but applies the choker's decisions while waiting, on connections
+    /// that upload.
+    async fn read_frame_or_decide(&mut self) -> Result<PeerMessage, Error> {
+        loop {
+            let Some((_, slot)) = &mut self.uploads else {
+                return self.read_frame().await;
+            };
+            let read = read_frame(&mut self.stream, &mut self.codec, &mut self.read_buffer);
+            let choke = tokio::select! {
+                message = read => return message,
+                choke = slot.decision() => choke,
+            };
+            self.apply_decision(choke).await?;
+        }
+    }
+
+    /// Returns the first queued message of `expected_type`, or reads messages until one
+    /// arrives. Keep-alives are dropped and choke, interest and availability updates are
+    /// applied to the connection. Requests are answered on connections that upload and
+    /// dropped while we choke the peer, cancels are dropped since requests never wait.
+    /// Anything else is queued for later reads, up to `MAX_PENDING_MESSAGES`. Being choked
+    /// while waiting for a piece is an error, since the peer discards our outstanding
+    /// requests. Safe to cancel: no message that was read gets lost.
+    pub async fn expect_message(
+        &mut self,
+        expected_type: PeerMessageType,
+    ) -> Result<PeerMessage, Error> {
+        let queued = self
+            .pending
+            .iter()
+            .position(|message| message.message_type() == Some(expected_type));
+        if let Some(message) = queued.and_then(|position| self.pending.remove(position)) {
+            return Ok(message);
+        }
+
+        loop {
+            let message = self.read_frame_or_decide().await?;
+            self.update_state(&message);
+            match message.message_type() {
+                Some(message_type) if message_type == expected_type => return Ok(message),
+                Some(PeerMessageType::Choke) if expected_type == PeerMessageType::Piece => {
+                    return Err(Error::Choked)
+                }
+                None
+                | Some(PeerMessageType::Choke)
+                | Some(PeerMessageType::Unchoke)
+                | Some(PeerMessageType::Interested)
+                | Some(PeerMessageType::NotInterested)
+                | Some(PeerMessageType::Have)
+                | Some(PeerMessageType::Bitfield) => {}
+                Some(PeerMessageType::Request) if self.uploads.is_some() => {
+                    if let PeerMessage::Request(request) = message {
+                        if !self.am_choking {
+                            self.serve_request(request).await?;
+                        }
+                    }
+                }
+                Some(PeerMessageType::Request) if self.am_choking => {}
+                Some(PeerMessageType::Cancel) => {}
+                Some(_) if self.pending.len() >= MAX_PENDING_MESSAGES => {
+                    return Err(unexpected_message(expected_type, &message))
+                }
+                Some(_) => self.pending.push_back(message),
+            }
+        }
+    }
+
+    /// Applies choke, interest and piece availability messages to the connection state.
+    pub(crate) fn update_state(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::Interested | PeerMessage::NotInterested => {
+                self.peer_interested = *message == PeerMessage::Interested;
+                if let Some((_, slot)) = &self.uploads {
+                    slot.set_interested(self.peer_interested);
+                }
+            }
+            PeerMessage::Have(piece_index) => self.set_piece(*piece_index as usize),
+            PeerMessage::Bitfield(bitfield) => {
+                self.bitfield = bitfield.clone();
+                if let Some(num_pieces) = self.num_pieces {
+                    self.bitfield.resize(num_pieces.div_ceil(8), 0);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn read_frame(
+    stream: &mut TcpStream,
+    codec: &mut PeerCodec,
+    read_buffer: &mut BytesMut,
+) -> Result<PeerMessage, Error> {
+    loop {
+        if let Some(message) = codec.decode(read_buffer)? {
+            return Ok(message);
+        }
+        let read = timeout(READ_TIMEOUT, stream.read_buf(read_buffer))
+            .await
+            .map_err(io::Error::from)??;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+pub(crate) fn unexpected_message(expected: PeerMessageType, actual: &PeerMessage) -> Error {
+    let actual = actual
+        .message_type()
+        .map_or(0, |message_type| message_type as u8);
+    Error::UnexpectedPeerMessage(expected as u8, actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn connected_pair() -> (PeerConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (PeerConnection::new(client), server)
     }
 
-    pub fn expect_message(&mut self, expected_type: PeerMessageType) -> Result<PeerMessage, Error> {
-        let message = self.read_message()?;
-        if message.id != expected_type {
-            return Err(Error::UnexpectedPeerMessage(
-                expected_type as u8,
-                message.id as u8,
-            ));
+    #[test]
+    fn test_peer_message_round_trip() {
+        let messages = [
+            PeerMessage::Choke,
+            PeerMessage::NotInterested,
+            PeerMessage::Have(7),
+            PeerMessage::Bitfield(vec![0b1010_0000]),
+            PeerMessage::Request(RequestPayload::new(1, 2, 3)),
+            PeerMessage::Piece(PiecePayload {
+                index: 1,
+                begin: 16384,
+                block: vec![1, 2, 3],
+            }),
+            PeerMessage::Cancel(RequestPayload::new(1, 2, 3)),
+            PeerMessage::Port(6881),
+            PeerMessage::Extended(0, b"de".to_vec()),
+        ];
+        for message in messages {
+            let bytes = message.as_bytes();
+            let length = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            assert_eq!(length, bytes.len() - 4);
+            assert_eq!(PeerMessage::from_bytes(&bytes[4..]).unwrap(), message);
         }
-        Ok(message)
+
+        assert_eq!(PeerMessage::KeepAlive.as_bytes(), [0, 0, 0, 0]);
+        assert_eq!(
+            PeerMessage::from_bytes(&[]).unwrap(),
+            PeerMessage::KeepAlive
+        );
+        assert!(PeerMessage::from_bytes(&[4, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_codec_waits_for_complete_frames() {
+        let mut codec = PeerCodec;
+        let mut buffer = BytesMut::new();
+        codec.encode(&PeerMessage::Have(3), &mut buffer);
+        codec.encode(&PeerMessage::KeepAlive, &mut buffer);
+        let mut partial = buffer.split_to(6);
+
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buffer);
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some(PeerMessage::Have(3))
+        );
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some(PeerMessage::KeepAlive)
+        );
+        assert!(partial.is_empty());
+
+        let mut oversized = BytesMut::from(&u32::MAX.to_be_bytes()[..]);
+        assert!(matches!(
+            codec.decode(&mut oversized),
+            Err(Error::MessageTooLarge(u32::MAX))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expect_message_skips_and_queues_unrelated_messages() {
+        let (mut connection, mut server) = connected_pair().await;
+        connection.set_num_pieces(10);
+        for message in [
+            PeerMessage::KeepAlive,
+            PeerMessage::Have(9),
+            PeerMessage::Extended(1, b"de".to_vec()),
+            PeerMessage::Unchoke,
+            PeerMessage::Port(6881),
+        ] {
+            server.write_all(&message.as_bytes()).await.unwrap();
+        }
+
+        assert_eq!(
+            connection
+                .expect_message(PeerMessageType::Port)
+                .await
+                .unwrap(),
+            PeerMessage::Port(6881)
+        );
+        assert!(connection.has_piece(9));
+        assert!(!connection.peer_choking);
+        assert_eq!(
+            connection.read_message().await.unwrap(),
+            PeerMessage::Extended(1, b"de".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_have_is_ignored() {
+        let (mut connection, mut server) = connected_pair().await;
+        for message in [
+            PeerMessage::Have(3),
+            PeerMessage::Have(0xFFFF_FFFF),
+            PeerMessage::Unchoke,
+        ] {
+            server.write_all(&message.as_bytes()).await.unwrap();
+        }
+        connection
+            .expect_message(PeerMessageType::Unchoke)
+            .await
+            .unwrap();
+        assert!(!connection.has_piece(3));
+        assert!(connection.bitfield.is_empty());
+
+        connection.set_num_pieces(10);
+        for message in [
+            PeerMessage::Have(3),
+            PeerMessage::Have(10),
+            PeerMessage::Have(0xFFFF_FFFF),
+            PeerMessage::Bitfield(vec![0xff; 1000]),
+            PeerMessage::Have(9),
+            PeerMessage::Port(6881),
+        ] {
+            server.write_all(&message.as_bytes()).await.unwrap();
+        }
+        connection
+            .expect_message(PeerMessageType::Port)
+            .await
+            .unwrap();
+        assert_eq!(connection.bitfield.len(), 2);
+        assert!(connection.has_piece(9) && !connection.has_piece(10));
+    }
+
+    #[tokio::test]
+    async fn test_pending_messages_are_bounded() {
+        let (mut connection, mut server) = connected_pair().await;
+        let request = PeerMessage::Request(RequestPayload::new(0, 0, BLOCK_SIZE));
+        let mut flood = request.as_bytes().repeat(2 * MAX_PENDING_MESSAGES);
+        flood.extend(PeerMessage::Unchoke.as_bytes());
+        server.write_all(&flood).await.unwrap();
+        // Requests to a peer we choke are never served, so they aren't kept.
+        connection
+            .expect_message(PeerMessageType::Unchoke)
+            .await
+            .unwrap();
+        assert!(connection.pending.is_empty());
+
+        connection.am_choking = false;
+        let flood = PeerMessage::Port(6881)
+            .as_bytes()
+            .repeat(MAX_PENDING_MESSAGES + 1);
+        server.write_all(&flood).await.unwrap();
+        assert!(matches!(
+            connection.expect_message(PeerMessageType::Piece).await,
+            Err(Error::UnexpectedPeerMessage(7, 9))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_choke_while_waiting_for_piece() {
+        let (mut connection, mut server) = connected_pair().await;
+        server
+            .write_all(&PeerMessage::Choke.as_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(
+            connection.expect_message(PeerMessageType::Piece).await,
+            Err(Error::Choked)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_piece_pipelines_requests() {
+        let (mut connection, server) = connected_pair().await;
+        connection.pipeline_depth = 3;
+        let piece_size = 3 * BLOCK_SIZE as usize + 100;
+        let data: Vec<u8> = (0..piece_size).map(|i| i as u8).collect();
+
+        let expected = data.clone();
+        let seeder = tokio::spawn(async move {
+            let mut server = PeerConnection::new(server);
+            // Answer only once the whole window is in flight, newest request first.
+            for window in [3, 1] {
+                let mut requests = Vec::new();
+                for _ in 0..window {
+                    match server.read_message().await.unwrap() {
+                        PeerMessage::Request(request) => requests.push(request),
+                        message => panic!("unexpected message {:?}", message),
+                    }
+                }
+                for request in requests.into_iter().rev() {
+                    let begin = request.begin as usize;
+                    let block = data[begin..begin + request.length as usize].to_vec();
+                    let piece = PiecePayload {
+                        index: request.index,
+                        begin: request.begin,
+                        block,
+                    };
+                    server.send(&PeerMessage::Piece(piece)).await.unwrap();
+                }
+            }
+        });
+
+        let piece = connection.download_piece(2, piece_size).await.unwrap();
+        seeder.await.unwrap();
+        assert_eq!(piece, expected);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_download_sends_cancel() {
+        let (mut connection, server) = connected_pair().await;
+        let piece_size = 2 * BLOCK_SIZE as usize;
+        let (requested, cancelled) = tokio::sync::oneshot::channel();
+
+        let seeder = tokio::spawn(async move {
+            let mut server = PeerConnection::new(server);
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                requests.push(server.read_message().await.unwrap());
+            }
+            requested.send(()).unwrap();
+            for request in requests {
+                let PeerMessage::Request(request) = request else {
+                    panic!("expected a request, got {:?}", request);
+                };
+                assert_eq!(
+                    server.read_message().await.unwrap(),
+                    PeerMessage::Cancel(request)
+                );
+            }
+        });
+
+        let piece = connection
+            .download_piece_until(0, piece_size, async {
+                cancelled.await.unwrap();
+            })
+            .await
+            .unwrap();
+        assert_eq!(piece, None);
+        seeder.await.unwrap();
     }
 }
@@ -0,0 +1,169 @@
+use crate::random::random_index;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Pending,
+    /// Being downloaded by this many peers. More than one only happens in endgame mode.
+    Downloading(usize),
+    Complete,
+}
+
+/// Chooses the piece each peer downloads next: a random one until the first piece is in,
+/// to get going quickly, then the rarest among connected peers. Once every missing piece
+/// is being downloaded it enters endgame mode and hands out pieces that are already in
+/// flight on other peers, so a slow peer can't hold up the end of the download.
+pub(crate) struct PiecePicker {
+    states: Vec<PieceState>,
+    /// Number of connected peers that have each piece.
+    availability: Vec<u32>,
+    completed: usize,
+}
+
+impl PiecePicker {
+    pub(crate) fn new(num_pieces: usize) -> Self {
+        Self {
+            states: vec![PieceState::Pending; num_pieces],
+            availability: vec![0; num_pieces],
+            completed: 0,
+        }
+    }
+
+    /// Records that a peer has a piece, from its `Bitfield` or a `Have`.
+    pub(crate) fn add_availability(&mut self, piece_index: usize) {
+        self.availability[piece_index] += 1;
+    }
+
+    /// Forgets a piece of a peer that went away.
+    pub(crate) fn remove_availability(&mut self, piece_index: usize) {
+        self.availability[piece_index] = self.availability[piece_index].saturating_sub(1);
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.completed == self.states.len()
+    }
+
+    pub(crate) fn is_piece_complete(&self, piece_index: usize) -> bool {
+        self.states[piece_index] == PieceState::Complete
+    }
+
+    pub(crate) fn in_endgame(&self) -> bool {
+        !self.is_complete() && !self.states.contains(&PieceState::Pending)
+    }
+
+    /// Whether a peer has any piece we still lack, now or after a failed download.
+    pub(crate) fn can_use(&self, has_piece: impl Fn(usize) -> bool) -> bool {
+        (0..self.states.len()).any(|i| has_piece(i) && !self.is_piece_complete(i))
+    }
+
+    /// Picks the next piece for a peer, or `None` if it has nothing to offer right now.
+    pub(crate) fn pick(&mut self, has_piece: impl Fn(usize) -> bool) -> Option<usize> {
+        let pending: Vec<usize> = (0..self.states.len())
+            .filter(|&i| self.states[i] == PieceState::Pending && has_piece(i))
+            .collect();
+
+        let piece_index = if !pending.is_empty() {
+            let candidates = if self.completed == 0 {
+                pending
+            } else {
+                let rarest = pending.iter().map(|&i| self.availability[i]).min()?;
+                pending
+                    .into_iter()
+                    .filter(|&i| self.availability[i] == rarest)
+                    .collect()
+            };
+            candidates[random_index(candidates.len())]
+        } else if self.in_endgame() {
+            // Join the download that the fewest peers are working on.
+            (0..self.states.len())
+                .filter(|&i| has_piece(i))
+                .filter_map(|i| match self.states[i] {
+                    PieceState::Downloading(peers) => Some((peers, i)),
+                    _ => None,
+                })
+                .min()?
+                .1
+        } else {
+            return None;
+        };
+
+        self.states[piece_index] = match self.states[piece_index] {
+            PieceState::Downloading(peers) => PieceState::Downloading(peers + 1),
+            _ => PieceState::Downloading(1),
+        };
+        Some(piece_index)
+    }
+
+    /// Marks a piece as downloaded and verified. Returns `false` if another peer already
+    /// completed it.
+    pub(crate) fn complete(&mut self, piece_index: usize) -> bool {
+        if self.is_piece_complete(piece_index) {
+            return false;
+        }
+        self.states[piece_index] = PieceState::Complete;
+        self.completed += 1;
+        true
+    }
+
+    /// Gives up one download of a piece. It becomes pending again unless other peers are
+    /// still working on it.
+    pub(crate) fn abort(&mut self, piece_index: usize) {
+        self.states[piece_index] = match self.states[piece_index] {
+            PieceState::Downloading(1) => PieceState::Pending,
+            PieceState::Downloading(peers) => PieceState::Downloading(peers - 1),
+            state => state,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_picks_rarest_piece_after_the_first() {
+        let mut picker = PiecePicker::new(4);
+        for (piece_index, peers) in [(0, 3), (1, 1), (2, 2), (3, 3)] {
+            for _ in 0..peers {
+                picker.add_availability(piece_index);
+            }
+        }
+
+        let first = picker.pick(|_| true).unwrap();
+        assert!(picker.complete(first));
+
+        let rarest = if first == 1 { 2 } else { 1 };
+        assert_eq!(picker.pick(|_| true), Some(rarest));
+        // Pieces the peer doesn't have are never picked.
+        assert_eq!(picker.pick(|i| i == first || i == rarest), None);
+    }
+
+    #[test]
+    fn test_endgame_shares_pieces_in_flight() {
+        let mut picker = PiecePicker::new(2);
+        let first = picker.pick(|_| true).unwrap();
+        let second = picker.pick(|_| true).unwrap();
+        assert!(picker.in_endgame());
+
+        assert!(picker.complete(first));
+        assert_eq!(picker.pick(|_| true), Some(second));
+
+        // The first peer to finish wins, the other download is abandoned.
+        assert!(picker.complete(second));
+        assert!(!picker.complete(second));
+        picker.abort(second);
+        assert!(picker.is_complete());
+        assert_eq!(picker.pick(|_| true), None);
+    }
+
+    #[test]
+    fn test_aborted_piece_becomes_pending_again() {
+        let mut picker = PiecePicker::new(2);
+        assert_eq!(picker.pick(|i| i == 0), Some(0));
+        assert_eq!(picker.pick(|i| i == 0), None);
+        assert!(picker.can_use(|i| i == 0));
+
+        picker.abort(0);
+        assert!(!picker.in_endgame());
+        assert_eq!(picker.pick(|i| i == 0), Some(0));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+
+use crate::{
+    deserializer, serializer,
+    storage::{self, FileStorage, Preallocation, Storage},
+    torrent::Torrent,
+    Error,
+};
+
+/// Piece completion saved next to a download, so an interrupted download can resume without
+/// hashing everything again.
+#[derive(Deserialize, Serialize)]
+struct ResumeState {
+    #[serde(with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    /// Completed pieces, one bit per piece as in a `Bitfield` message.
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    /// Size and modification time of each file when the state was saved. If any file
+    /// changed since, the state is stale and the data is rechecked instead. A file modified
+    /// no earlier than the state file itself may have changed within the same timestamp
+    /// tick on filesystems with coarse timestamps, so the state isn't trusted then either.
+    files: Vec<FileStamp>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq)]
+struct FileStamp {
+    length: u64,
+    modified: u64,
+}
+
+/// Path of the state file for a download: the output path with `.state` appended.
+fn state_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".state");
+    PathBuf::from(path)
+}
+
+/// Opens the storage for a download, keeping data from an earlier run, and returns which
+/// pieces are already complete. Uses the state file when it is still valid and falls back
+/// to hashing the existing data.
+pub(crate) fn open(
+    torrent: &Torrent,
+    output: &Path,
+    preallocation: Preallocation,
+) -> Result<(FileStorage, Vec<bool>), Error> {
+    let existed = output.exists();
+    let saved = load(torrent, output);
+
+    let mut storage = FileStorage::open_or_create(torrent, output, preallocation)?;
+    let have = match saved {
+        Some(have) => have,
+        None if existed => recheck(torrent, &mut storage),
+        None => vec![false; torrent.num_pieces()],
+    };
+    Ok((storage, have))
+}
+
+/// Hashes every piece of the local data. Pieces that can't be read, for example because a
+/// file is too short, count as invalid.
+pub(crate) fn recheck(torrent: &Torrent, storage: &mut dyn Storage) -> Vec<bool> {
+    let piece_hashes = torrent.piece_hashes();
+    (0..torrent.num_pieces())
+        .map(|piece_index| {
+            storage
+                .read_block(piece_index, 0, torrent.piece_size(piece_index))
+                .is_ok_and(|piece| {
+                    piece_hashes[piece_index] == hex::encode(sha1::Sha1::digest(&piece))
+                })
+        })
+        .collect()
+}
+
+/// Writes the state file, replacing the previous one atomically.
+pub(crate) fn save(torrent: &Torrent, output: &Path, have: &[bool]) -> Result<(), Error> {
+    let mut pieces = vec![0u8; have.len().div_ceil(8)];
+    for piece_index in (0..have.len()).filter(|&i| have[i]) {
+        pieces[piece_index / 8] |= 0x80 >> (piece_index % 8);
+    }
+    let state = ResumeState {
+        info_hash: torrent.info_hash().to_vec(),
+        pieces,
+        files: file_stamps(torrent, output)?,
+    };
+
+    let path = state_path(output);
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, serializer::to_bytes(&state)?)?;
+    fs::rename(&temp_path, &path)?;
+    Ok(())
+}
+
+/// Reads the state file, or returns `None` if it is missing, unreadable or stale.
+fn load(torrent: &Torrent, output: &Path) -> Option<Vec<bool>> {
+    let path = state_path(output);
+    let saved_at = modified(&fs::metadata(&path).ok()?).ok()?;
+    let bytes = fs::read(&path).ok()?;
+    let state: ResumeState = deserializer::from_bytes(&bytes).ok()?;
+
+    let num_pieces = torrent.num_pieces();
+    let files = file_stamps(torrent, output).ok()?;
+    if state.info_hash != torrent.info_hash()
+        || state.pieces.len() != num_pieces.div_ceil(8)
+        || state.files != files
+        || files.iter().any(|file| file.modified >= saved_at)
+    {
+        return None;
+    }
+
+    let have = (0..num_pieces)
+        .map(|i| state.pieces[i / 8] & (0x80 >> (i % 8)) != 0)
+        .collect();
+    Some(have)
+}
+
+fn file_stamps(torrent: &Torrent, output: &Path) -> Result<Vec<FileStamp>, Error> {
+    storage::file_paths(torrent, output)?
+        .into_iter()
+        .map(|(path, _)| {
+            let metadata = fs::metadata(path)?;
+            Ok(FileStamp {
+                length: metadata.len(),
+                modified: modified(&metadata)?,
+            })
+        })
+        .collect()
+}
+
+/// Modification time in nanoseconds since the epoch.
+fn modified(metadata: &fs::Metadata) -> Result<u64, Error> {
+    Ok(metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+
+    fn set_state_modified(output: &Path, modified: SystemTime) {
+        let state_file = fs::File::options()
+            .write(true)
+            .open(state_path(output))
+            .unwrap();
+        state_file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_resume_from_state_file_and_recheck() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let options = CreateOptions {
+            announce: "http://tracker/announce".to_owned(),
+            announce_list: Vec::new(),
+            comment: None,
+            piece_length: Some(16 * 1024),
+            private: false,
+        };
+        let torrent = create_torrent(&source, &options).unwrap();
+
+        // A fresh download starts with nothing.
+        let output = dir.path().join("output.bin");
+        let (mut storage, have) = open(&torrent, &output, Preallocation::Sparse).unwrap();
+        assert_eq!(have, [false; 4]);
+
+        storage.write_piece(1, &data[16 * 1024..32 * 1024]).unwrap();
+        drop(storage);
+
+        // The state file is trusted over the data, as long as it was saved after the data
+        // last changed. Its timestamp is set explicitly, coarse filesystems may not tell
+        // the two writes apart.
+        save(&torrent, &output, &[false, true, false, true]).unwrap();
+        let data_modified = fs::metadata(&output).unwrap().modified().unwrap();
+        set_state_modified(&output, data_modified + Duration::from_secs(1));
+        let (_, have) = open(&torrent, &output, Preallocation::Sparse).unwrap();
+        assert_eq!(have, [false, true, false, true]);
+
+        // Without a usable state file the data is hashed instead.
+        fs::write(state_path(&output), b"garbage").unwrap();
+        let (mut storage, have) = open(&torrent, &output, Preallocation::Sparse).unwrap();
+        assert_eq!(have, [false, true, false, false]);
+
+        // A state file that no longer matches the data is ignored.
+        save(&torrent, &output, &[true, true, true, true]).unwrap();
+        storage.write_piece(2, &data[32 * 1024..48 * 1024]).unwrap();
+        let (_, have) = open(&torrent, &output, Preallocation::Sparse).unwrap();
+        assert_eq!(have, [false, true, true, false]);
+
+        // Even if the write lands within the state file's timestamp tick, leaving the
+        // data's stamp unchanged, the state isn't trusted.
+        save(&torrent, &output, &[true, true, true, true]).unwrap();
+        let data_modified = fs::metadata(&output).unwrap().modified().unwrap();
+        set_state_modified(&output, data_modified);
+        let (_, have) = open(&torrent, &output, Preallocation::Sparse).unwrap();
+        assert_eq!(have, [false, true, true, false]);
+    }
+}
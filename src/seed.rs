@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use sha1::Digest;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+use crate::{
+    choke::{ChokeSlot, ChokedPeers, ChokerConfig, Ranking},
+    peer::{
+        BlockSource, PeerConnection, PeerMessage, PeerMessageType, PiecePayload, RequestPayload,
+        BLOCK_SIZE,
+    },
+    storage::Storage,
+    torrent::Torrent,
+    Error,
+};
+
+/// Largest block we serve. Peers asking for more are disconnected.
+const MAX_REQUEST_LENGTH: u32 = 8 * BLOCK_SIZE;
+
+/// A torrent whose local data we serve to other peers, while downloading it or seeding.
+pub(crate) struct SharedTorrent {
+    pub(crate) torrent: Torrent,
+    storage: Mutex<Box<dyn Storage>>,
+    /// Pieces that passed hash verification.
+    verified: Vec<AtomicBool>,
+    /// Pieces verified after opening, in order, for announcing them to peers.
+    completed: Mutex<Vec<usize>>,
+    /// Bytes of piece data read for other peers.
+    pub(crate) uploaded: AtomicU64,
+}
+
+impl SharedTorrent {
+    /// Serves the pieces marked in `have`, which are trusted to be verified already.
+    pub(crate) fn new(torrent: Torrent, storage: impl Storage + 'static, have: &[bool]) -> Self {
+        Self {
+            verified: have.iter().map(|&have| AtomicBool::new(have)).collect(),
+            torrent,
+            storage: Mutex::new(Box::new(storage)),
+            completed: Mutex::new(Vec::new()),
+            uploaded: AtomicU64::new(0),
+        }
+    }
+
+    /// Hashes every piece of the local data. Only pieces that match are served.
+    pub(crate) fn verify(
+        torrent: Torrent,
+        mut storage: impl Storage + 'static,
+    ) -> Result<Self, Error> {
+        let mut have = vec![false; torrent.num_pieces()];
+        for (piece_index, have) in have.iter_mut().enumerate() {
+            let piece = storage.read_block(piece_index, 0, torrent.piece_size(piece_index))?;
+            *have = torrent.piece_hash(piece_index) == sha1::Sha1::digest(&piece).as_slice();
+        }
+        Ok(Self::new(torrent, storage, &have))
+    }
+
+    pub(crate) fn has_piece(&self, piece_index: usize) -> bool {
+        self.verified
+            .get(piece_index)
+            .is_some_and(|verified| verified.load(Ordering::Relaxed))
+    }
+
+    /// The verified pieces in wire format.
+    pub(crate) fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.torrent.num_pieces().div_ceil(8)];
+        for piece_index in (0..self.torrent.num_pieces()).filter(|&i| self.has_piece(i)) {
+            bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        }
+        bitfield
+    }
+
+    pub(crate) fn num_verified(&self) -> usize {
+        (0..self.torrent.num_pieces())
+            .filter(|&piece_index| self.has_piece(piece_index))
+            .count()
+    }
+
+    /// Bytes still missing locally, as reported to the tracker.
+    pub(crate) fn left(&self) -> u64 {
+        (0..self.torrent.num_pieces())
+            .filter(|&piece_index| !self.has_piece(piece_index))
+            .map(|piece_index| self.torrent.piece_size(piece_index) as u64)
+            .sum()
+    }
+
+    /// Stores a piece that passed its hash check and starts serving it.
+    pub(crate) fn write_piece(&self, piece_index: usize, piece: &[u8]) -> Result<(), Error> {
+        self.storage
+            .lock()
+            .unwrap()
+            .write_piece(piece_index, piece)?;
+        self.verified[piece_index].store(true, Ordering::Relaxed);
+        self.completed.lock().unwrap().push(piece_index);
+        Ok(())
+    }
+
+    /// Pieces completed since the first `seen` ones.
+    pub(crate) fn completed_since(&self, seen: usize) -> Vec<usize> {
+        self.completed.lock().unwrap()[seen..].to_vec()
+    }
+}
+
+impl BlockSource for SharedTorrent {
+    fn read_block(&self, request: &RequestPayload) -> Result<Option<Vec<u8>>, Error> {
+        let piece_index = request.index as usize;
+        if piece_index >= self.torrent.num_pieces()
+            || !self.has_piece(piece_index)
+            || request.length == 0
+            || request.length > MAX_REQUEST_LENGTH
+            || request.begin as u64 + request.length as u64
+                > self.torrent.piece_size(piece_index) as u64
+        {
+            return Ok(None);
+        }
+
+        let block = self.storage.lock().unwrap().read_block(
+            piece_index,
+            request.begin as u64,
+            request.length as usize,
+        )?;
+        self.uploaded
+            .fetch_add(request.length as u64, Ordering::Relaxed);
+        Ok(Some(block))
+    }
+}
+
+/// A torrent we seed and the connections it has.
+struct Seeded {
+    shared: Arc<SharedTorrent>,
+    peers: Arc<ChokedPeers>,
+}
+
+/// Accepts incoming peer connections for the torrents it holds and uploads to them.
+pub(crate) struct Seeder {
+    peer_id: [u8; 20],
+    torrents: HashMap<[u8; 20], Seeded>,
+    choker_config: ChokerConfig,
+}
+
+impl Seeder {
+    pub(crate) fn new(peer_id: [u8; 20]) -> Self {
+        Self {
+            peer_id,
+            torrents: HashMap::new(),
+            choker_config: ChokerConfig::default(),
+        }
+    }
+
+    /// Sets the choker settings of the torrents added afterwards.
+    pub(crate) fn with_choker_config(mut self, choker_config: ChokerConfig) -> Self {
+        self.choker_config = choker_config;
+        self
+    }
+
+    pub(crate) fn add(&mut self, torrent: Arc<SharedTorrent>) {
+        let seeded = Seeded {
+            shared: Arc::clone(&torrent),
+            peers: Arc::new(ChokedPeers::new(self.choker_config)),
+        };
+        self.torrents.insert(torrent.torrent.info_hash(), seeded);
+    }
+
+    /// Accepts peers and runs the chokers until an error occurs on the listener.
+    pub(crate) async fn listen(self: Arc<Self>, listener: TcpListener) -> Result<(), Error> {
+        let mut chokers = JoinSet::new();
+        for seeded in self.torrents.values() {
+            let peers = Arc::clone(&seeded.peers);
+            chokers.spawn(async move { peers.run(Ranking::UploadRate).await });
+        }
+        self.accept(listener).await
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener) -> Result<(), Error> {
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            let seeder = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = seeder.serve(stream).await {
+                    eprintln!("Peer {} failed: {}", peer_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn serve(&self, stream: TcpStream) -> Result<(), Error> {
+        let (mut peer, info_hash) = PeerConnection::accept(stream, self.peer_id, |info_hash| {
+            self.torrents.contains_key(info_hash)
+        })
+        .await?;
+
+        let seeded = &self.torrents[&info_hash];
+        peer.set_num_pieces(seeded.shared.torrent.num_pieces());
+        self.serve_peer(peer, &seeded.shared, seeded.peers.join())
+            .await
+    }
+
+    async fn serve_peer(
+        &self,
+        mut peer: PeerConnection,
+        shared: &SharedTorrent,
+        mut slot: ChokeSlot,
+    ) -> Result<(), Error> {
+        peer.send(&PeerMessage::Bitfield(shared.bitfield())).await?;
+
+        loop {
+            let read = tokio::select! {
+                read = peer.read_message() => read,
+                choke = slot.decision() => {
+                    match (choke, peer.am_choking) {
+                        (true, false) => peer.choke().await?,
+                        (false, true) => peer.unchoke().await?,
+                        _ => {}
+                    }
+                    continue;
+                }
+            };
+
+            let message = match read {
+                Ok(message) => message,
+                Err(Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    return Ok(())
+                }
+                // Downloaders may go quiet for a while, keep the connection open.
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+                    peer.send(&PeerMessage::KeepAlive).await?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            peer.update_state(&message);
+
+            match message {
+                PeerMessage::Interested | PeerMessage::NotInterested => {
+                    slot.set_interested(peer.peer_interested);
+                }
+                // Requests that were in flight when we choked the peer are dropped.
+                PeerMessage::Request(request) if !peer.am_choking => {
+                    let block = shared
+                        .read_block(&request)?
+                        .ok_or(Error::InvalidMessagePayload(PeerMessageType::Request as u8))?;
+                    let piece = PiecePayload {
+                        index: request.index,
+                        begin: request.begin,
+                        block,
+                    };
+                    peer.send(&PeerMessage::Piece(piece)).await?;
+                    slot.add_uploaded(request.length as u64);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::SocketAddr, time::Duration};
+
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::storage::FileStorage;
+
+    const PEER_ID: [u8; 20] = *b"-TEST-00000000000000";
+
+    /// Writes three pieces of data and a torrent for them, with the second piece damaged.
+    fn damaged_torrent(dir: &tempfile::TempDir) -> (Torrent, Vec<u8>, Arc<SharedTorrent>) {
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let options = CreateOptions {
+            announce: "http://tracker/announce".to_owned(),
+            announce_list: Vec::new(),
+            comment: None,
+            piece_length: Some(BLOCK_SIZE as u64),
+            private: false,
+        };
+        let torrent = create_torrent(&path, &options).unwrap();
+
+        let mut damaged = data.clone();
+        damaged[BLOCK_SIZE as usize] ^= 0xff;
+        fs::write(&path, &damaged).unwrap();
+
+        let storage = FileStorage::open(&torrent, &path).unwrap();
+        let shared = Arc::new(SharedTorrent::verify(torrent.clone(), storage).unwrap());
+        (torrent, data, shared)
+    }
+
+    async fn spawn_seeder(shared: &Arc<SharedTorrent>, choker_config: ChokerConfig) -> SocketAddr {
+        let mut seeder = Seeder::new(*b"-SEED-00000000000000").with_choker_config(choker_config);
+        seeder.add(Arc::clone(shared));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(seeder).listen(listener));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_seeder_serves_verified_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data, shared) = damaged_torrent(&dir);
+        assert_eq!(shared.num_verified(), 2);
+        assert_eq!(shared.left(), BLOCK_SIZE as u64);
+        let addr = spawn_seeder(&shared, ChokerConfig::default()).await;
+
+        let mut peer = PeerConnection::connect(addr, torrent.info_hash(), PEER_ID)
+            .await
+            .unwrap();
+        peer.prepare_download().await.unwrap();
+        // The damaged piece must not be advertised.
+        assert!(peer.has_piece(0) && !peer.has_piece(1) && peer.has_piece(2));
+
+        let piece = peer.download_piece(2, torrent.piece_size(2)).await.unwrap();
+        assert_eq!(piece, data[2 * BLOCK_SIZE as usize..]);
+        assert_eq!(shared.uploaded.load(Ordering::Relaxed), piece.len() as u64);
+
+        let unknown = PeerConnection::connect(addr, [7; 20], PEER_ID).await;
+        assert!(unknown.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_choker_limits_unchoked_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, _, shared) = damaged_torrent(&dir);
+        // No scheduled round after the first, so every round is one triggered by a change
+        // of interest and the outcome doesn't depend on timing.
+        let choker_config = ChokerConfig {
+            regular_slots: 1,
+            round_interval: Duration::from_secs(3600),
+            optimistic_rounds: 1000,
+            ..ChokerConfig::default()
+        };
+        let addr = spawn_seeder(&shared, choker_config).await;
+
+        // Rates are all zero, so the first peer keeps the regular slot and the second the
+        // optimistic unchoke it got before the third showed interest.
+        let mut peers = Vec::new();
+        for i in 0..3 {
+            let mut peer = PeerConnection::connect(addr, torrent.info_hash(), PEER_ID)
+                .await
+                .unwrap();
+            peer.read_bitfield().await.unwrap();
+            peer.send(&PeerMessage::Interested).await.unwrap();
+            if i < 2 {
+                peer.expect_message(PeerMessageType::Unchoke).await.unwrap();
+            }
+            peers.push(peer);
+        }
+        // Only bounds how long we look for a wrong unchoke, a slow machine can't fail it.
+        let unchoke = peers[2].expect_message(PeerMessageType::Unchoke);
+        assert!(tokio::time::timeout(Duration::from_millis(100), unchoke)
+            .await
+            .is_err());
+
+        // A peer losing interest frees its slot for the one left waiting.
+        peers[1].send(&PeerMessage::NotInterested).await.unwrap();
+        peers[2]
+            .expect_message(PeerMessageType::Unchoke)
+            .await
+            .unwrap();
+    }
+}
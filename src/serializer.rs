@@ -0,0 +1,445 @@
+use std::io::Write;
+
+use serde::ser::{self, Serialize};
+
+use crate::Error;
+
+/// Serializes `value` to bencode. Dictionary keys are written in raw byte order and
+/// `None` fields are left out, since bencode has no null.
+pub(crate) fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer::default();
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+#[derive(Default)]
+pub(crate) struct Serializer {
+    output: Vec<u8>,
+}
+
+impl Serializer {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        write!(self.output, "{}:", bytes.len())?;
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_integer(&mut self, n: impl std::fmt::Display) -> Result<(), Error> {
+        write!(self.output, "i{}e", n)?;
+        Ok(())
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = ListSerializer<'a>;
+    type SerializeTuple = ListSerializer<'a>;
+    type SerializeTupleStruct = ListSerializer<'a>;
+    type SerializeTupleVariant = ListSerializer<'a>;
+    type SerializeMap = DictSerializer<'a>;
+    type SerializeStruct = DictSerializer<'a>;
+    type SerializeStructVariant = DictSerializer<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.write_integer(v as u8)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.write_integer(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), Error> {
+        Err(Error::Custom(
+            "bencode has no floating point type".to_owned(),
+        ))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), Error> {
+        Err(Error::Custom(
+            "bencode has no floating point type".to_owned(),
+        ))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write_bytes(v.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.output.push(b'd');
+        self.write_bytes(variant.as_bytes())?;
+        value.serialize(&mut *self)?;
+        self.output.push(b'e');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListSerializer<'a>, Error> {
+        self.output.push(b'l');
+        Ok(ListSerializer {
+            serializer: self,
+            variant: false,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<ListSerializer<'a>, Error> {
+        self.output.push(b'd');
+        self.write_bytes(variant.as_bytes())?;
+        self.output.push(b'l');
+        Ok(ListSerializer {
+            serializer: self,
+            variant: true,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictSerializer<'a>, Error> {
+        Ok(DictSerializer {
+            serializer: self,
+            entries: Vec::new(),
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<DictSerializer<'a>, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DictSerializer<'a>, Error> {
+        Ok(DictSerializer {
+            serializer: self,
+            entries: Vec::new(),
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+pub(crate) struct ListSerializer<'a> {
+    serializer: &'a mut Serializer,
+    /// Whether the list is the value of an enum variant wrapped in a single-key dict.
+    variant: bool,
+}
+
+impl<'a> ListSerializer<'a> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        self.serializer.output.push(b'e');
+        if self.variant {
+            self.serializer.output.push(b'e');
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for ListSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for ListSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for ListSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for ListSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+/// Buffers every entry so the keys can be sorted before the dict is written.
+pub(crate) struct DictSerializer<'a> {
+    serializer: &'a mut Serializer,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+    variant: Option<&'static str>,
+}
+
+impl<'a> DictSerializer<'a> {
+    fn entry<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        let value = to_bytes(value)?;
+        // `None` serializes to nothing, in which case the key is left out as well.
+        if !value.is_empty() {
+            self.entries.push((key, value));
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        let output = &mut self.serializer.output;
+        if let Some(variant) = self.variant {
+            output.push(b'd');
+            write!(output, "{}:{}", variant.len(), variant)?;
+        }
+
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        output.push(b'd');
+        for (key, value) in &self.entries {
+            write!(output, "{}:", key.len())?;
+            output.extend_from_slice(key);
+            output.extend_from_slice(value);
+        }
+        output.push(b'e');
+
+        if self.variant.is_some() {
+            output.push(b'e');
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the raw bytes of a dict key, which must serialize to a bencode string.
+fn key_bytes<T: Serialize + ?Sized>(key: &T) -> Result<Vec<u8>, Error> {
+    let encoded = to_bytes(key)?;
+    let colon_index = encoded
+        .iter()
+        .position(|&b| b == b':')
+        .filter(|&i| encoded[..i].iter().all(u8::is_ascii_digit) && i > 0)
+        .ok_or_else(|| Error::InvalidDictKey(String::from_utf8_lossy(&encoded).into_owned()))?;
+    Ok(encoded[colon_index + 1..].to_vec())
+}
+
+impl<'a> ser::SerializeMap for DictSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key_bytes(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::Custom("dict value serialized before its key".to_owned()))?;
+        self.entry(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for DictSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for DictSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Serialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        private: Option<u8>,
+        length: Option<u64>,
+    }
+
+    #[test]
+    fn test_serialize_struct_sorts_keys_and_skips_none() {
+        let info = Info {
+            name: "a".to_owned(),
+            piece_length: 16,
+            pieces: vec![0xff, 0x00],
+            private: None,
+            length: Some(3),
+        };
+        assert_eq!(
+            to_bytes(&info).unwrap(),
+            b"d6:lengthi3e4:name1:a12:piece lengthi16e6:pieces2:\xff\x00e"
+        );
+    }
+
+    #[test]
+    fn test_serialize_map_and_list() {
+        let map = HashMap::from([("b", vec![1, 2]), ("a", vec![])]);
+        assert_eq!(to_bytes(&map).unwrap(), b"d1:ale1:bli1ei2eee");
+    }
+}
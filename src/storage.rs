@@ -0,0 +1,348 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{torrent::Torrent, Error};
+
+/// Where the pieces of a torrent are kept. Offsets are relative to the start of a piece,
+/// implementations map them onto the torrent's content.
+pub(crate) trait Storage: Send {
+    /// Writes verified data starting at `begin` within a piece.
+    fn write_block(&mut self, piece_index: usize, begin: u64, data: &[u8]) -> Result<(), Error>;
+
+    /// Reads `length` bytes starting at `begin` within a piece.
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, Error>;
+
+    fn write_piece(&mut self, piece_index: usize, piece: &[u8]) -> Result<(), Error> {
+        self.write_block(piece_index, 0, piece)
+    }
+}
+
+/// How the space for a download is reserved up front.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Preallocation {
+    /// Only set the file sizes, the filesystem allocates blocks as pieces arrive.
+    #[default]
+    Sparse,
+    /// Write zeros over the whole content so the download can't run out of disk space.
+    Full,
+}
+
+struct StorageFile {
+    file: fs::File,
+    /// Offset of the file's first byte within the torrent's concatenated content.
+    offset: u64,
+    length: u64,
+}
+
+/// Maps pieces onto the files of a torrent. Single-file torrents live at `output`
+/// directly, multi-file torrents are laid out below `output` as a directory.
+pub(crate) struct FileStorage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+}
+
+impl FileStorage {
+    /// Opens the files of a torrent for reading and writing, keeping existing data. Missing
+    /// files are created and files of the wrong size are resized.
+    pub(crate) fn open_or_create(
+        torrent: &Torrent,
+        output: &Path,
+        preallocation: Preallocation,
+    ) -> Result<Self, Error> {
+        let files = file_paths(torrent, output)?;
+        Self::with_files(&files, torrent.info.piece_length as u64, |path, length| {
+            create_file(path, length, preallocation)
+        })
+    }
+
+    /// Opens the existing files of a torrent for reading.
+    pub(crate) fn open(torrent: &Torrent, output: &Path) -> Result<Self, Error> {
+        let files = file_paths(torrent, output)?;
+        Self::with_files(&files, torrent.info.piece_length as u64, |path, _| {
+            Ok(fs::File::open(path)?)
+        })
+    }
+
+    /// Creates a file holding a single piece of `length` bytes, truncating any existing data.
+    pub(crate) fn create_piece(path: &Path, length: u64) -> Result<Self, Error> {
+        let file = fs::File::create(path)?;
+        file.set_len(length)?;
+        Ok(Self {
+            files: vec![StorageFile {
+                file,
+                offset: 0,
+                length,
+            }],
+            piece_length: length,
+        })
+    }
+
+    fn with_files(
+        files: &[(PathBuf, u64)],
+        piece_length: u64,
+        open_file: impl Fn(&Path, u64) -> Result<fs::File, Error>,
+    ) -> Result<Self, Error> {
+        let mut storage_files = Vec::new();
+        let mut offset = 0;
+
+        for (path, length) in files {
+            storage_files.push(StorageFile {
+                file: open_file(path, *length)?,
+                offset,
+                length: *length,
+            });
+            offset += length;
+        }
+
+        Ok(Self {
+            files: storage_files,
+            piece_length,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    /// Writes a block, splitting it across every file its byte range overlaps.
+    fn write_block(&mut self, piece_index: usize, begin: u64, data: &[u8]) -> Result<(), Error> {
+        let block_start = piece_index as u64 * self.piece_length + begin;
+        let block_end = block_start + data.len() as u64;
+
+        for storage_file in &mut self.files {
+            let file_end = storage_file.offset + storage_file.length;
+            if file_end <= block_start || storage_file.offset >= block_end {
+                continue;
+            }
+
+            let start = block_start.max(storage_file.offset);
+            let end = block_end.min(file_end);
+            let data = &data[(start - block_start) as usize..(end - block_start) as usize];
+
+            storage_file
+                .file
+                .seek(SeekFrom::Start(start - storage_file.offset))?;
+            storage_file.file.write_all(data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a block across file boundaries.
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let block_start = piece_index as u64 * self.piece_length + begin;
+        let block_end = block_start + length as u64;
+        let mut block = vec![0u8; length];
+
+        for storage_file in &mut self.files {
+            let file_end = storage_file.offset + storage_file.length;
+            if file_end <= block_start || storage_file.offset >= block_end {
+                continue;
+            }
+
+            let start = block_start.max(storage_file.offset);
+            let end = block_end.min(file_end);
+            let data = &mut block[(start - block_start) as usize..(end - block_start) as usize];
+
+            storage_file
+                .file
+                .seek(SeekFrom::Start(start - storage_file.offset))?;
+            storage_file.file.read_exact(data)?;
+        }
+
+        Ok(block)
+    }
+}
+
+/// Keeps the whole content in memory, for tests.
+#[cfg(test)]
+pub(crate) struct MemoryStorage {
+    data: Vec<u8>,
+    piece_length: u64,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub(crate) fn new(torrent: &Torrent) -> Self {
+        Self {
+            data: vec![0; torrent.info.length() as usize],
+            piece_length: torrent.info.piece_length as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn write_block(&mut self, piece_index: usize, begin: u64, data: &[u8]) -> Result<(), Error> {
+        let start = (piece_index as u64 * self.piece_length + begin) as usize;
+        self.data
+            .get_mut(start..start + data.len())
+            .ok_or(Error::InvalidPieceLength(data.len() as u64))?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let start = (piece_index as u64 * self.piece_length + begin) as usize;
+        self.data
+            .get(start..start + length)
+            .map(<[u8]>::to_vec)
+            .ok_or(Error::InvalidPieceLength(length as u64))
+    }
+}
+
+/// Where each file of a torrent is stored, with its length, in piece order.
+pub(crate) fn file_paths(torrent: &Torrent, output: &Path) -> Result<Vec<(PathBuf, u64)>, Error> {
+    match &torrent.info.files {
+        Some(files) => files
+            .iter()
+            .map(|file| Ok((output.join(sanitize_path(&file.path)?), file.length as u64)))
+            .collect(),
+        None => Ok(vec![(output.to_path_buf(), torrent.info.length() as u64)]),
+    }
+}
+
+/// Turns the path components of a torrent file into a relative path that stays below the
+/// download directory. `.` and `..` are dropped and separators inside a component are
+/// replaced, so a malicious torrent can't write elsewhere.
+pub(crate) fn sanitize_path(components: &[String]) -> Result<PathBuf, Error> {
+    let path: PathBuf = components
+        .iter()
+        .filter(|component| !matches!(component.as_str(), "" | "." | ".."))
+        .map(|component| component.replace(['/', '\\', '\0'], "_"))
+        .collect();
+
+    let is_relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if path.as_os_str().is_empty() || !is_relative {
+        return Err(Error::InvalidPath(components.join("/")));
+    }
+    Ok(path)
+}
+
+fn create_file(path: &Path, length: u64, preallocation: Preallocation) -> Result<fs::File, Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    // Resizing touches the modification time, which fast resume relies on.
+    let current_length = file.metadata()?.len();
+    if current_length == length {
+        return Ok(file);
+    }
+    if preallocation == Preallocation::Full && current_length < length {
+        // Only the new part is zeroed, data from an earlier run is kept.
+        file.seek(SeekFrom::Start(current_length))?;
+        let zeros = vec![0u8; 64 * 1024];
+        let mut remaining = length - current_length;
+        while remaining > 0 {
+            let chunk = remaining.min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[..chunk])?;
+            remaining -= chunk as u64;
+        }
+    } else {
+        file.set_len(length)?;
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{TorrentFile, TorrentInfo};
+
+    fn multi_file_torrent(files: &[(&[&str], i64)]) -> Torrent {
+        let files = files
+            .iter()
+            .map(|(path, length)| TorrentFile {
+                length: *length,
+                path: path.iter().map(|component| component.to_string()).collect(),
+            })
+            .collect();
+        let info = TorrentInfo {
+            length: None,
+            name: "multi".to_owned(),
+            piece_length: 4,
+            pieces: vec![0; 20 * 3],
+            files: Some(files),
+            private: None,
+        };
+        Torrent::new("http://tracker/announce".to_owned(), info).unwrap()
+    }
+
+    #[test]
+    fn test_pieces_span_file_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = multi_file_torrent(&[(&["a.txt"], 3), (&["sub", "b.bin"], 6), (&["c"], 1)]);
+
+        let mut storage =
+            FileStorage::open_or_create(&torrent, dir.path(), Preallocation::Sparse).unwrap();
+        storage.write_piece(0, b"abcd").unwrap();
+        storage.write_block(1, 2, b"gh").unwrap();
+        storage.write_block(1, 0, b"ef").unwrap();
+        storage.write_piece(2, b"ij").unwrap();
+
+        assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"abc");
+        assert_eq!(fs::read(dir.path().join("sub/b.bin")).unwrap(), b"defghi");
+        assert_eq!(fs::read(dir.path().join("c")).unwrap(), b"j");
+        assert_eq!(storage.read_block(0, 2, 5).unwrap(), b"cdefg");
+
+        let mut memory = MemoryStorage::new(&torrent);
+        memory.write_piece(0, b"abcd").unwrap();
+        memory.write_block(1, 2, b"gh").unwrap();
+        assert_eq!(memory.read_block(0, 2, 4).unwrap(), b"cd\0\0");
+        assert!(memory.write_block(2, 1, b"xy").is_err());
+    }
+
+    #[test]
+    fn test_full_preallocation_keeps_existing_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = multi_file_torrent(&[(&["a"], 5), (&["b"], 5)]);
+        fs::write(dir.path().join("a"), b"abc").unwrap();
+
+        FileStorage::open_or_create(&torrent, dir.path(), Preallocation::Full).unwrap();
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"abc\0\0");
+        assert_eq!(fs::read(dir.path().join("b")).unwrap(), [0; 5]);
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        let path = |components: &[&str]| {
+            let components: Vec<String> = components.iter().map(|c| c.to_string()).collect();
+            sanitize_path(&components)
+        };
+
+        assert_eq!(path(&["dir", "file"]).unwrap(), Path::new("dir/file"));
+        assert_eq!(
+            path(&["..", "..", "etc", "passwd"]).unwrap(),
+            Path::new("etc/passwd")
+        );
+        assert_eq!(path(&["/etc/passwd"]).unwrap(), Path::new("_etc_passwd"));
+        assert_eq!(path(&["a\\..\\b"]).unwrap(), Path::new("a_.._b"));
+        assert!(path(&["..", "."]).is_err());
+        assert!(path(&[]).is_err());
+    }
+}
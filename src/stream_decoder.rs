@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read},
+};
+
+use crate::{bencode::Bencode, Error};
+
+/// Bounds applied while decoding untrusted input.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// Maximum number of nested lists and dicts.
+    pub(crate) max_depth: usize,
+    /// Maximum length of a single byte string.
+    pub(crate) max_string_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_string_length: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Decodes bencode values incrementally from any reader, without buffering the whole input.
+pub(crate) struct StreamDecoder<R: Read> {
+    reader: BufReader<R>,
+    limits: Limits,
+    depth: usize,
+}
+
+impl<R: Read> StreamDecoder<R> {
+    pub(crate) fn new(reader: R, limits: Limits) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            limits,
+            depth: 0,
+        }
+    }
+
+    /// Decodes the next value, or returns `None` if the reader is exhausted. Values may be
+    /// concatenated back to back in the stream, optionally separated by whitespace.
+    pub(crate) fn decode(&mut self) -> Result<Option<Bencode>, Error> {
+        while self.peek()?.is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.reader.consume(1);
+        }
+
+        match self.peek()? {
+            Some(_) => self.decode_value().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Error> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn next_byte(&mut self) -> Result<u8, Error> {
+        let byte = self.peek()?.ok_or(Error::UnexpectedEOF)?;
+        self.reader.consume(1);
+        Ok(byte)
+    }
+
+    fn decode_value(&mut self) -> Result<Bencode, Error> {
+        match self.peek()?.ok_or(Error::UnexpectedEOF)? {
+            digit if digit.is_ascii_digit() => self.parse_bencode_string().map(Bencode::Bytes),
+            b'i' => {
+                self.reader.consume(1);
+                self.parse_number(b'e').map(Bencode::Int)
+            }
+            b'l' => {
+                self.reader.consume(1);
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()?.ok_or(Error::UnexpectedEOF)? != b'e' {
+                    list.push(self.decode_value()?);
+                }
+                self.reader.consume(1);
+                self.depth -= 1;
+                Ok(Bencode::List(list))
+            }
+            b'd' => {
+                self.reader.consume(1);
+                self.enter()?;
+                let mut dict = BTreeMap::new();
+                while self.peek()?.ok_or(Error::UnexpectedEOF)? != b'e' {
+                    let key = match self.peek()? {
+                        Some(digit) if digit.is_ascii_digit() => self.parse_bencode_string()?,
+                        _ => {
+                            let key = self.decode_value()?;
+                            return Err(Error::InvalidDictKey(format!("{:?}", key)));
+                        }
+                    };
+                    let value = self.decode_value()?;
+                    dict.insert(key, value);
+                }
+                self.reader.consume(1);
+                self.depth -= 1;
+                Ok(Bencode::Dict(dict))
+            }
+            other => Err(Error::InvalidBencodeType(other)),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > self.limits.max_depth {
+            return Err(Error::NestingTooDeep(self.limits.max_depth));
+        }
+        Ok(())
+    }
+
+    /// Reads an integer up to `terminator`, consuming the terminator.
+    fn parse_number(&mut self, terminator: u8) -> Result<i64, Error> {
+        let mut digits = Vec::new();
+        loop {
+            match self.next_byte()? {
+                byte if byte == terminator => break,
+                // Longer than any i64, so it can't be a valid number.
+                _ if digits.len() > 20 => {
+                    return Err(Error::NotNumber(
+                        String::from_utf8_lossy(&digits).into_owned(),
+                    ))
+                }
+                byte => digits.push(byte),
+            }
+        }
+
+        let number_str = std::str::from_utf8(&digits).map_err(|_| Error::InvalidUTF8)?;
+        number_str
+            .parse::<i64>()
+            .map_err(|_| Error::NotNumber(number_str.to_string()))
+    }
+
+    fn parse_bencode_string(&mut self) -> Result<Vec<u8>, Error> {
+        let length = self.parse_number(b':')?;
+        let length = usize::try_from(length).map_err(|_| Error::NotNumber(length.to_string()))?;
+        if length > self.limits.max_string_length {
+            return Err(Error::StringTooLong(length));
+        }
+
+        let mut bytes = Vec::new();
+        (&mut self.reader)
+            .take(length as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() != length {
+            return Err(Error::BencodeStringLengthMismatch);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_decoder_reads_consecutive_values() {
+        let input: &[u8] = b"d3:fooli1ei2eee4:spam\ni-3e\n";
+        let mut stream_decoder = StreamDecoder::new(input, Limits::default());
+        assert_eq!(
+            stream_decoder.decode().unwrap(),
+            Some(Bencode::Dict(BTreeMap::from([(
+                b"foo".to_vec(),
+                Bencode::List(vec![Bencode::Int(1), Bencode::Int(2)])
+            )])))
+        );
+        assert_eq!(
+            stream_decoder.decode().unwrap(),
+            Some(Bencode::Bytes(b"spam".to_vec()))
+        );
+        assert_eq!(stream_decoder.decode().unwrap(), Some(Bencode::Int(-3)));
+        assert_eq!(stream_decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn test_stream_decoder_enforces_limits() {
+        let limits = Limits {
+            max_depth: 3,
+            max_string_length: 4,
+        };
+
+        let deep = b"l".repeat(1_000_000);
+        let mut stream_decoder = StreamDecoder::new(deep.as_slice(), limits);
+        assert!(matches!(
+            stream_decoder.decode(),
+            Err(Error::NestingTooDeep(3))
+        ));
+
+        let mut stream_decoder = StreamDecoder::new(&b"lll3:abceee"[..], limits);
+        assert!(stream_decoder.decode().is_ok());
+
+        let mut stream_decoder = StreamDecoder::new(&b"99999999999:abc"[..], limits);
+        assert!(matches!(
+            stream_decoder.decode(),
+            Err(Error::StringTooLong(99999999999))
+        ));
+    }
+
+    #[test]
+    fn test_stream_decoder_truncated_input() {
+        let mut stream_decoder = StreamDecoder::new(&b"l3:abc"[..], Limits::default());
+        assert!(matches!(stream_decoder.decode(), Err(Error::UnexpectedEOF)));
+
+        let mut stream_decoder = StreamDecoder::new(&b"5:abc"[..], Limits::default());
+        assert!(matches!(
+            stream_decoder.decode(),
+            Err(Error::BencodeStringLengthMismatch)
+        ));
+    }
+}
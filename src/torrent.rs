@@ -1,91 +1,149 @@
-use serde_json::Value;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
 use sha1::Digest;
 
+use crate::{deserializer::Deserializer, serializer};
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Torrent {
+    /// Empty for trackerless torrents, which find peers through the DHT.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub comment: Option<String>,
+    #[serde(rename = "created by")]
+    pub created_by: Option<String>,
+    #[serde(rename = "creation date")]
+    pub creation_date: Option<i64>,
     pub info: TorrentInfo,
+    /// DHT nodes as `[host, port]` pairs, to bootstrap from for trackerless torrents.
+    pub nodes: Option<Vec<(String, i64)>>,
+    /// SHA-1 of the `info` dictionary exactly as it was encoded in the torrent file.
+    #[serde(skip)]
+    info_hash: [u8; 20],
 }
 
+#[derive(Clone, Deserialize, Serialize)]
 pub struct TorrentInfo {
-    pub length: i64,
+    /// Length of a single-file torrent; multi-file torrents list lengths in `files`.
+    pub length: Option<i64>,
     pub name: String,
+    #[serde(rename = "piece length")]
     pub piece_length: i64,
+    #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
+    pub files: Option<Vec<TorrentFile>>,
+    pub private: Option<u8>,
 }
 
-impl Torrent {
-    pub fn from_bencode(value: Value) -> Result<Self, crate::Error> {
-        let announce = value["announce"]
-            .as_str()
-            .ok_or(crate::Error::MissingField("announce".to_owned()))?
-            .to_string();
-
-        let info = value["info"]
-            .as_object()
-            .ok_or(crate::Error::MissingField("info".to_owned()))?;
-
-        let length = info["length"]
-            .as_i64()
-            .ok_or(crate::Error::MissingField("length".to_owned()))?;
-
-        let name = info["name"]
-            .as_str()
-            .ok_or(crate::Error::MissingField("name".to_owned()))?
-            .to_string();
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TorrentFile {
+    pub length: i64,
+    pub path: Vec<String>,
+}
 
-        let piece_length = info["piece length"]
-            .as_i64()
-            .ok_or(crate::Error::MissingField("piece length".to_owned()))?;
+impl TorrentInfo {
+    /// Total length of the content, summed across `files` for multi-file torrents.
+    pub fn length(&self) -> i64 {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None => self.length.unwrap_or_default(),
+        }
+    }
 
-        let pieces = info["pieces"]
-            .as_array()
-            .ok_or(crate::Error::MissingField("pieces".to_owned()))?
+    /// Rejects info dicts the piece arithmetic can't work with. They may come from a peer,
+    /// through magnet metadata.
+    fn validate(&self) -> Result<(), crate::Error> {
+        if self.length.is_none() && self.files.is_none() {
+            return Err(crate::Error::MissingField("length".to_owned()));
+        }
+        if self.piece_length <= 0 {
+            return Err(crate::Error::InvalidTorrent(format!(
+                "piece length {}",
+                self.piece_length
+            )));
+        }
+        let mut lengths = self
+            .length
             .iter()
-            .filter_map(|v| v.as_u64().map(|n| n as u8))
-            .collect();
+            .chain(self.files.iter().flatten().map(|f| &f.length));
+        if lengths.any(|&length| length < 0) {
+            return Err(crate::Error::InvalidTorrent("negative length".to_owned()));
+        }
+        let num_pieces = (self.length() as u64).div_ceil(self.piece_length as u64);
+        if self.pieces.len() as u64 != num_pieces * 20 {
+            return Err(crate::Error::InvalidTorrent(format!(
+                "{} bytes of piece hashes for {} pieces",
+                self.pieces.len(),
+                num_pieces
+            )));
+        }
+        Ok(())
+    }
+}
 
+impl Torrent {
+    pub fn new(announce: String, info: TorrentInfo) -> Result<Self, crate::Error> {
+        let info_hash = sha1::Sha1::digest(serializer::to_bytes(&info)?).into();
         Ok(Torrent {
             announce,
-            info: TorrentInfo {
-                length,
-                name,
-                piece_length,
-                pieces,
-            },
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info,
+            nodes: None,
+            info_hash,
         })
     }
 
-    pub fn info_hash_hex_string(&self) -> Result<String, crate::Error> {
-        Ok(self
-            .info_hash()?
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<Vec<String>>()
-            .join(""))
+    /// Builds a torrent from a raw bencoded info dict, e.g. one fetched via a magnet link.
+    pub fn from_info_bytes(announce: String, info_bytes: &[u8]) -> Result<Self, crate::Error> {
+        let info: TorrentInfo = crate::deserializer::from_bytes(info_bytes)?;
+        info.validate()?;
+
+        let mut torrent = Self::new(announce, info)?;
+        torrent.info_hash = sha1::Sha1::digest(info_bytes).into();
+        Ok(torrent)
+    }
+
+    pub fn to_bencode(&self) -> Result<Vec<u8>, crate::Error> {
+        serializer::to_bytes(self)
+    }
+
+    pub fn from_bencode(buffer: &[u8]) -> Result<Self, crate::Error> {
+        let mut deserializer = Deserializer::new(buffer);
+        let mut torrent: Torrent = deserializer.deserialize()?;
+        torrent.info.validate()?;
+
+        let raw_info = deserializer
+            .raw_value("info")
+            .ok_or(crate::Error::MissingField("info".to_owned()))?;
+        torrent.info_hash = sha1::Sha1::digest(raw_info).into();
+
+        Ok(torrent)
+    }
+
+    /// Every file in the torrent as `(relative path, length)`, in piece order. A single-file
+    /// torrent yields one entry named after the torrent.
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        match &self.info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| (file.path.iter().collect(), file.length as u64))
+                .collect(),
+            None => vec![(PathBuf::from(&self.info.name), self.info.length() as u64)],
+        }
+    }
+
+    pub fn info_hash_hex_string(&self) -> String {
+        hex::encode(self.info_hash)
     }
 
-    pub fn info_hash(&self) -> Result<[u8; 20], crate::Error> {
-        let encoded_info =
-            crate::encoder::Encoder::encode(&Value::Object(serde_json::Map::from_iter(vec![
-                ("length".to_string(), Value::Number(self.info.length.into())),
-                ("name".to_string(), Value::String(self.info.name.clone())),
-                (
-                    "piece length".to_string(),
-                    Value::Number(self.info.piece_length.into()),
-                ),
-                (
-                    "pieces".to_string(),
-                    Value::Array(
-                        self.info
-                            .pieces
-                            .iter()
-                            .map(|&b| Value::Number(b.into()))
-                            .collect(),
-                    ),
-                ),
-            ])))?;
-
-        Ok(sha1::Sha1::digest(&encoded_info).into())
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
     }
 
     pub fn piece_hashes(&self) -> Vec<String> {
@@ -102,11 +160,57 @@ impl Torrent {
             })
     }
 
+    /// The SHA-1 the data of a piece must hash to.
+    pub fn piece_hash(&self, piece_index: usize) -> &[u8] {
+        &self.info.pieces[piece_index * 20..piece_index * 20 + 20]
+    }
+
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        if piece_index == self.num_pieces() - 1 && self.info.length() % self.info.piece_length != 0
+        {
+            (self.info.length() % self.info.piece_length) as usize
+        } else {
+            self.info.piece_length as usize
+        }
+    }
+
     pub fn num_pieces(&self) -> usize {
-        if self.info.length % self.info.piece_length == 0 {
-            (self.info.length / self.info.piece_length) as usize
+        if self.info.length() % self.info.piece_length == 0 {
+            (self.info.length() / self.info.piece_length) as usize
         } else {
-            (self.info.length / self.info.piece_length) as usize + 1
+            (self.info.length() / self.info.piece_length) as usize + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_bytes(piece_length: i64, num_hashes: usize) -> Vec<u8> {
+        let mut bytes = format!(
+            "d8:announce3:url4:infod6:lengthi10e4:name1:a12:piece lengthi{}e6:pieces{}:",
+            piece_length,
+            num_hashes * 20
+        )
+        .into_bytes();
+        bytes.extend(vec![7; num_hashes * 20]);
+        bytes.extend(b"ee");
+        bytes
+    }
+
+    #[test]
+    fn test_piece_layout_is_validated() {
+        let torrent = Torrent::from_bencode(&torrent_bytes(4, 3)).unwrap();
+        assert_eq!(torrent.num_pieces(), 3);
+        assert_eq!(torrent.piece_size(2), 2);
+        assert_eq!(torrent.piece_hash(2), [7; 20]);
+
+        for (piece_length, num_hashes) in [(0, 3), (-4, 3), (4, 2), (4, 4)] {
+            assert!(matches!(
+                Torrent::from_bencode(&torrent_bytes(piece_length, num_hashes)),
+                Err(crate::Error::InvalidTorrent(_))
+            ));
         }
     }
 }
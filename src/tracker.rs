@@ -1,72 +1,1019 @@
-use std::net::SocketAddrV4;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use serde::Serialize;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 
-use crate::decoder;
+use crate::{
+    deserializer,
+    random::shuffle,
+    stream_decoder::Limits,
+    torrent::Torrent,
+    udp_tracker::{UdpAnnounceRequest, UdpTracker},
+};
 
-#[derive(Serialize)]
+#[derive(Deserialize)]
+struct TrackerResponse {
+    /// Set when the tracker refused the announce, in which case nothing else is present.
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    peers: Option<PeerList>,
+    /// IPv6 peers in compact form, from BEP 7.
+    peers6: Option<serde_bytes::ByteBuf>,
+    interval: Option<u64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+}
+
+/// The `peers` of an announce response: compact, or a list of dictionaries from trackers
+/// that ignore `compact=1`.
+enum PeerList {
+    Compact(Vec<u8>),
+    Dictionaries(Vec<PeerEntry>),
+}
+
+/// A peer in dictionary form. Its `peer id` is ignored.
+#[derive(Deserialize)]
+struct PeerEntry {
+    ip: String,
+    port: u16,
+}
+
+impl PeerList {
+    /// Entries whose `ip` is a DNS name rather than an address are skipped.
+    fn addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            PeerList::Compact(peers) => parse_compact_peers(peers),
+            PeerList::Dictionaries(entries) => entries
+                .iter()
+                .filter_map(|entry| {
+                    let ip: IpAddr = entry.ip.parse().ok()?;
+                    Some(SocketAddr::new(ip, entry.port))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PeerListVisitor;
+
+        impl<'de> Visitor<'de> for PeerListVisitor {
+            type Value = PeerList;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a compact peer string or a list of peer dictionaries")
+            }
+
+            fn visit_bytes<E: de::Error>(self, peers: &[u8]) -> Result<PeerList, E> {
+                Ok(PeerList::Compact(peers.to_vec()))
+            }
+
+            // Compact peers that happen to be valid UTF-8 arrive as a string.
+            fn visit_str<E: de::Error>(self, peers: &str) -> Result<PeerList, E> {
+                self.visit_bytes(peers.as_bytes())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PeerList, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = seq.next_element()? {
+                    entries.push(entry);
+                }
+                Ok(PeerList::Dictionaries(entries))
+            }
+        }
+
+        deserializer.deserialize_any(PeerListVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    files: Option<HashMap<serde_bytes::ByteBuf, ScrapeStats>>,
+}
+
+/// Swarm statistics of one torrent, from a scrape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub(crate) struct ScrapeStats {
+    /// Number of seeders.
+    pub(crate) complete: u64,
+    /// Number of leechers.
+    pub(crate) incomplete: u64,
+    /// Number of times the torrent was downloaded to completion.
+    pub(crate) downloaded: u64,
+}
+
+/// Port we listen on for incoming peers unless configured otherwise.
+pub(crate) const DEFAULT_PORT: u16 = 6881;
+
+/// How often to re-announce when the tracker doesn't say.
+pub(crate) const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How long an HTTP tracker gets to answer.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest HTTP tracker response we read, bodies beyond this are refused unread.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Tracker responses are at most a few levels deep (scrape: files, hash, stats).
+const RESPONSE_LIMITS: Limits = Limits {
+    max_depth: 8,
+    max_string_length: MAX_RESPONSE_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    fn udp_code(self) -> u32 {
+        match self {
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
+/// What a tracker told us about a swarm.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct AnnounceResponse {
+    /// How long to wait before the next regular announce.
+    pub(crate) interval: Duration,
+    /// Announcing more often than this is not allowed.
+    pub(crate) min_interval: Option<Duration>,
+    /// Sent back to the tracker on later announces.
+    pub(crate) tracker_id: Option<String>,
+    /// Number of seeders.
+    pub(crate) complete: Option<u64>,
+    /// Number of leechers.
+    pub(crate) incomplete: Option<u64>,
+    pub(crate) warning: Option<String>,
+    pub(crate) peers: Vec<SocketAddr>,
+}
+
+#[derive(Clone, Serialize)]
 pub(crate) struct Tracker {
     peer_id: String,
-    port: u16,
-    uploaded: u64,
-    downloaded: u64,
-    left: u64,
+    pub(crate) port: u16,
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
     compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) event: Option<Event>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trackerid: Option<String>,
 }
 
 impl Tracker {
     pub(crate) fn new(left: u64) -> Self {
         Self {
             peer_id: "00112233445566778899".to_owned(),
-            port: 6881,
+            port: DEFAULT_PORT,
             uploaded: 0,
             downloaded: 0,
             left,
             compact: 1,
+            event: None,
+            trackerid: None,
         }
     }
 
-    pub(crate) fn get_peers(
+    /// Announces to an `http://` or `udp://` tracker.
+    pub(crate) async fn announce(
         &self,
         announce_url: &str,
-        info_hash: &str,
-    ) -> Result<Vec<SocketAddrV4>, crate::Error> {
-        let url = format!(
-            "{}?info_hash={}&{}",
-            announce_url,
-            info_hash,
+        info_hash: &[u8; 20],
+    ) -> Result<AnnounceResponse, crate::Error> {
+        if announce_url.starts_with("udp://") {
+            let mut tracker = UdpTracker::connect(announce_url).await?;
+            self.announce_udp(&mut tracker, info_hash).await
+        } else {
+            self.announce_http(announce_url, info_hash).await
+        }
+    }
+
+    async fn announce_http(
+        &self,
+        announce_url: &str,
+        info_hash: &[u8; 20],
+    ) -> Result<AnnounceResponse, crate::Error> {
+        let query = format!(
+            "info_hash={}&{}",
+            url_encode(info_hash),
             serde_urlencoded::to_string(self).expect("Tracker is not url encodable")
         );
 
-        let response = reqwest::blocking::get(url)?;
-        let bytes = response.bytes().unwrap();
-        let mut decoder = decoder::Decoder::new(&bytes);
-        let decoded_value = decoder.decode().unwrap();
-
-        decoded_value
-            .as_object()
-            .map(|obj| {
-                obj["peers"].as_array().map(|arr| {
-                    arr.chunks(6)
-                        .map(|chunk| {
-                            let ip_bytes: [u8; 4] = [
-                                chunk[0].as_u64().unwrap_or_default() as u8,
-                                chunk[1].as_u64().unwrap_or_default() as u8,
-                                chunk[2].as_u64().unwrap_or_default() as u8,
-                                chunk[3].as_u64().unwrap_or_default() as u8,
-                            ];
-                            let ip = std::net::Ipv4Addr::from(ip_bytes);
-                            let port = u16::from_be_bytes([
-                                chunk[4].as_u64().unwrap_or_default() as u8,
-                                chunk[5].as_u64().unwrap_or_default() as u8,
-                            ]);
-
-                            SocketAddrV4::new(ip, port)
-                        })
-                        .collect::<Vec<_>>()
-                })
-            })
-            .unwrap_or_default()
-            .ok_or(crate::Error::NoPeers)
+        let bytes = http_get(&with_query(announce_url, &query)).await?;
+        let response: TrackerResponse = parse_response(&bytes)?;
+        if let Some(reason) = response.failure_reason {
+            return Err(crate::Error::TrackerFailure(reason));
+        }
+        if response.peers.is_none() && response.peers6.is_none() {
+            return Err(crate::Error::InvalidTrackerResponse(
+                "no peers in response".to_owned(),
+            ));
+        }
+
+        let mut peers = response.peers.map_or_else(Vec::new, |peers| peers.addrs());
+        if let Some(peers6) = response.peers6 {
+            peers.extend(parse_compact_peers6(&peers6));
+        }
+        Ok(AnnounceResponse {
+            interval: response
+                .interval
+                .map_or(DEFAULT_INTERVAL, Duration::from_secs),
+            min_interval: response.min_interval.map(Duration::from_secs),
+            tracker_id: response.tracker_id,
+            complete: response.complete,
+            incomplete: response.incomplete,
+            warning: response.warning_message,
+            peers,
+        })
+    }
+
+    async fn announce_udp(
+        &self,
+        tracker: &mut UdpTracker,
+        info_hash: &[u8; 20],
+    ) -> Result<AnnounceResponse, crate::Error> {
+        let request = UdpAnnounceRequest {
+            info_hash: *info_hash,
+            peer_id: self
+                .peer_id
+                .as_bytes()
+                .try_into()
+                .expect("peer id is 20 bytes"),
+            downloaded: self.downloaded,
+            left: self.left,
+            uploaded: self.uploaded,
+            event: self.event.map_or(0, Event::udp_code),
+            port: self.port,
+        };
+        let response = tracker.announce(&request).await?;
+        // Trackers reached over IPv6 answer with IPv6 peers, as BEP 15 specifies.
+        let peers = if tracker.is_ipv6() {
+            parse_compact_peers6(&response.peers)
+        } else {
+            parse_compact_peers(&response.peers)
+        };
+        Ok(AnnounceResponse {
+            interval: Duration::from_secs(response.interval.into()),
+            complete: Some(response.seeders.into()),
+            incomplete: Some(response.leechers.into()),
+            peers,
+            ..AnnounceResponse::default()
+        })
+    }
+}
+
+/// The trackers of a torrent, grouped in tiers as in BEP 12. Trackers are tried in order
+/// within a tier, and one that answers is moved to the front of its tier for next time.
+pub(crate) struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    /// Tracker ids handed out by each tracker, echoed back on later announces.
+    tracker_ids: HashMap<String, String>,
+    /// Clients of the `udp://` trackers, kept so their connection ids get reused.
+    udp_trackers: HashMap<String, UdpTracker>,
+}
+
+impl TrackerList {
+    /// Shuffles each tier, so clients spread their load over equivalent trackers.
+    pub(crate) fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        for tier in &mut tiers {
+            tier.retain(|url| !url.is_empty());
+            shuffle(tier);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+            udp_trackers: HashMap::new(),
+        }
+    }
+
+    /// Uses `announce-list` when present, `announce` otherwise.
+    pub(crate) fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(torrent_tiers(torrent))
+    }
+
+    /// Announces to the first working tracker of every tier and merges what they return:
+    /// all their peers, the shortest interval and the longest minimum interval. Fails only
+    /// if no tracker answered at all.
+    pub(crate) async fn announce(
+        &mut self,
+        tracker: &Tracker,
+        info_hash: &[u8; 20],
+    ) -> Result<AnnounceResponse, crate::Error> {
+        let mut merged: Option<AnnounceResponse> = None;
+        let mut seen = HashSet::new();
+        let mut last_error = crate::Error::NoPeers;
+
+        for tier in &mut self.tiers {
+            for position in 0..tier.len() {
+                let url = &tier[position];
+                let mut tracker = tracker.clone();
+                tracker.trackerid = self.tracker_ids.get(url).cloned();
+                let udp_trackers = &mut self.udp_trackers;
+                let result = async {
+                    if url.starts_with("udp://") {
+                        let udp_tracker = udp_tracker(udp_trackers, url).await?;
+                        tracker.announce_udp(udp_tracker, info_hash).await
+                    } else {
+                        tracker.announce(url, info_hash).await
+                    }
+                };
+                let mut response = match result.await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        eprintln!("Tracker {} failed: {}", url, e);
+                        last_error = e;
+                        continue;
+                    }
+                };
+
+                if let Some(warning) = &response.warning {
+                    eprintln!("Tracker {} warning: {}", url, warning);
+                }
+                if let Some(tracker_id) = &response.tracker_id {
+                    self.tracker_ids.insert(url.clone(), tracker_id.clone());
+                }
+                let url = tier.remove(position);
+                tier.insert(0, url);
+
+                response.peers.retain(|peer| seen.insert(*peer));
+                match &mut merged {
+                    None => merged = Some(response),
+                    Some(merged) => {
+                        merged.interval = merged.interval.min(response.interval);
+                        merged.min_interval = merged.min_interval.max(response.min_interval);
+                        merged.peers.extend(response.peers);
+                    }
+                }
+                break;
+            }
+        }
+
+        merged.ok_or(last_error)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Scrapes the first tracker that answers, tier by tier.
+    pub(crate) async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, crate::Error> {
+        let mut last_error = crate::Error::NoPeers;
+        for url in self.tiers.iter().flatten() {
+            let udp_trackers = &mut self.udp_trackers;
+            let result = async {
+                if url.starts_with("udp://") {
+                    scrape_udp(udp_tracker(udp_trackers, url).await?, info_hashes).await
+                } else {
+                    scrape(url, info_hashes).await
+                }
+            };
+            match result.await {
+                Ok(stats) => return Ok(stats),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Announces without an event and returns the peers. Getting none fails with the
+    /// tracker's warning if it sent one, as that likely explains why.
+    pub(crate) async fn get_peers(
+        &mut self,
+        tracker: &Tracker,
+        info_hash: &[u8; 20],
+    ) -> Result<Vec<SocketAddr>, crate::Error> {
+        let response = self.announce(tracker, info_hash).await?;
+        if response.peers.is_empty() {
+            return Err(response
+                .warning
+                .map_or(crate::Error::NoPeers, crate::Error::TrackerWarning));
+        }
+        Ok(response.peers)
+    }
+}
+
+/// The client for a `udp://` tracker, created on first use.
+async fn udp_tracker<'a>(
+    udp_trackers: &'a mut HashMap<String, UdpTracker>,
+    url: &str,
+) -> Result<&'a mut UdpTracker, crate::Error> {
+    if !udp_trackers.contains_key(url) {
+        let udp_tracker = UdpTracker::connect(url).await?;
+        udp_trackers.insert(url.to_owned(), udp_tracker);
+    }
+    Ok(udp_trackers.get_mut(url).expect("inserted above"))
+}
+
+/// The tracker tiers of a torrent: `announce-list` when present, `announce` otherwise.
+pub(crate) fn torrent_tiers(torrent: &Torrent) -> Vec<Vec<String>> {
+    match &torrent.announce_list {
+        Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers.clone(),
+        _ => vec![vec![torrent.announce.clone()]],
+    }
+}
+
+/// Asks a tracker for the swarm statistics of several torrents at once, without
+/// announcing. The statistics come in the order of `info_hashes`, torrents the tracker
+/// doesn't know have empty swarms.
+pub(crate) async fn scrape(
+    announce_url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>, crate::Error> {
+    if announce_url.starts_with("udp://") {
+        let mut tracker = UdpTracker::connect(announce_url).await?;
+        return scrape_udp(&mut tracker, info_hashes).await;
+    }
+
+    let url = scrape_url(announce_url)
+        .ok_or_else(|| crate::Error::ScrapeNotSupported(announce_url.to_owned()))?;
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", url_encode(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let bytes = http_get(&with_query(&url, &query)).await?;
+    let response: ScrapeResponse = parse_response(&bytes)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(crate::Error::TrackerFailure(reason));
+    }
+    let files = response.files.ok_or_else(|| {
+        crate::Error::InvalidTrackerResponse("no files in scrape response".to_owned())
+    })?;
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            let info_hash = serde_bytes::ByteBuf::from(info_hash.to_vec());
+            files.get(&info_hash).copied().unwrap_or_default()
+        })
+        .collect())
+}
+
+async fn scrape_udp(
+    tracker: &mut UdpTracker,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>, crate::Error> {
+    let stats = tracker.scrape(info_hashes).await?;
+    Ok(stats
+        .into_iter()
+        .map(|stats| ScrapeStats {
+            complete: stats.seeders.into(),
+            incomplete: stats.leechers.into(),
+            downloaded: stats.completed.into(),
+        })
+        .collect())
+}
+
+/// Fetches a tracker URL. Anything but a 200 fails with the status and the start of the
+/// body, which usually says what went wrong.
+async fn http_get(url: &str) -> Result<Vec<u8>, crate::Error> {
+    let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+    let result = async {
+        let mut response = client.get(url).send().await?;
+        let status = response.status();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() > MAX_RESPONSE_SIZE {
+                return Ok(None);
+            }
+        }
+        Ok(Some((status, bytes)))
+    }
+    .await;
+    let (status, bytes) = result
+        .map_err(|e: reqwest::Error| {
+            if e.is_timeout() {
+                crate::Error::TrackerTimeout
+            } else {
+                crate::Error::Network(e)
+            }
+        })?
+        .ok_or_else(|| {
+            crate::Error::InvalidTrackerResponse(format!(
+                "response larger than {} bytes",
+                MAX_RESPONSE_SIZE
+            ))
+        })?;
+
+    if status != reqwest::StatusCode::OK {
+        // Some trackers explain a refusal with a bencoded failure reason.
+        if let Ok(TrackerResponse {
+            failure_reason: Some(reason),
+            ..
+        }) = parse_response(&bytes)
+        {
+            return Err(crate::Error::TrackerFailure(reason));
+        }
+        let body = String::from_utf8_lossy(&bytes[..bytes.len().min(200)]);
+        return Err(crate::Error::TrackerStatus(
+            status.as_u16(),
+            body.trim().to_owned(),
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Decodes a tracker response within `RESPONSE_LIMITS`, reporting anything that isn't the
+/// expected bencode as a malformed response.
+fn parse_response<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, crate::Error> {
+    deserializer::Deserializer::with_limits(bytes, RESPONSE_LIMITS)
+        .deserialize()
+        .map_err(|e| crate::Error::InvalidTrackerResponse(e.to_string()))
+}
+
+/// The scrape URL of an HTTP tracker, by convention its announce URL with the `announce`
+/// of the last path segment replaced by `scrape`. Trackers whose URL doesn't follow the
+/// convention don't support scraping.
+fn scrape_url(announce_url: &str) -> Option<String> {
+    let (base, last_segment) = announce_url.rsplit_once('/')?;
+    let rest = last_segment.strip_prefix("announce")?;
+    Some(format!("{}/scrape{}", base, rest))
+}
+
+fn with_query(url: &str, query: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, query)
+}
+
+/// Transfer counters reported on announce.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Transfer {
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+}
+
+/// Keeps a torrent announced over its lifetime: the lifecycle events, and regular
+/// announces in between at the pace the trackers ask for.
+pub(crate) struct Announcer {
+    trackers: TrackerList,
+    tracker: Tracker,
+    info_hash: [u8; 20],
+    interval: Duration,
+    last_announce: Option<Instant>,
+}
+
+impl Announcer {
+    pub(crate) fn new(torrent: &Torrent, port: u16) -> Self {
+        let mut tracker = Tracker::new(torrent.info.length() as u64);
+        tracker.port = port;
+        Self {
+            trackers: TrackerList::from_torrent(torrent),
+            tracker,
+            info_hash: torrent.info_hash(),
+            interval: DEFAULT_INTERVAL,
+            last_announce: None,
+        }
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.tracker.port
+    }
+
+    pub(crate) fn has_announced(&self) -> bool {
+        self.last_announce.is_some()
+    }
+
+    pub(crate) async fn announce(
+        &mut self,
+        event: Option<Event>,
+        transfer: Transfer,
+    ) -> Result<AnnounceResponse, crate::Error> {
+        self.tracker.event = event;
+        self.tracker.uploaded = transfer.uploaded;
+        self.tracker.downloaded = transfer.downloaded;
+        self.tracker.left = transfer.left;
+        self.last_announce = Some(Instant::now());
+        // Trackerless torrents have no one to announce to.
+        if self.trackers.is_empty() {
+            return Ok(AnnounceResponse::default());
+        }
+
+        let response = self
+            .trackers
+            .announce(&self.tracker, &self.info_hash)
+            .await?;
+        let interval = match response.interval {
+            Duration::ZERO => DEFAULT_INTERVAL,
+            interval => interval,
+        };
+        self.interval = interval.max(response.min_interval.unwrap_or_default());
+        Ok(response)
+    }
+
+    /// Time left until the next regular announce is due.
+    pub(crate) fn until_next(&self) -> Duration {
+        self.last_announce.map_or(Duration::ZERO, |last_announce| {
+            self.interval.saturating_sub(last_announce.elapsed())
+        })
+    }
+}
+
+/// Parses peers in compact form: 4 bytes of IPv4 address and 2 bytes of port each.
+fn parse_compact_peers(peers: &[u8]) -> Vec<SocketAddr> {
+    peers
+        .chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::new(ip.into(), port)
+        })
+        .collect()
+}
+
+/// Parses IPv6 peers in compact form: 16 bytes of address and 2 bytes of port each.
+fn parse_compact_peers6(peers: &[u8]) -> Vec<SocketAddr> {
+    peers
+        .chunks_exact(18)
+        .map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().expect("chunk is 18 bytes");
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        })
+        .collect()
+}
+
+/// Percent-encodes raw bytes for a tracker query string.
+pub(crate) fn url_encode(input: &[u8; 20]) -> String {
+    let unreserved_characters =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+    input
+        .iter()
+        .flat_map(|&byte| {
+            if unreserved_characters.contains(&byte) {
+                vec![byte as char]
+            } else {
+                format!("%{:02x}", byte).chars().collect()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::Error;
+
+    /// An HTTP tracker that answers every announce with `body` and passes on the query
+    /// strings it receives.
+    async fn spawn_http_tracker(body: Vec<u8>) -> (String, mpsc::UnboundedReceiver<String>) {
+        spawn_http_tracker_with_status("200 OK", body).await
+    }
+
+    async fn spawn_http_tracker_with_status(
+        status: &'static str,
+        body: Vec<u8>,
+    ) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let length = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..length]);
+                let query = request.split(' ').nth(1).unwrap_or_default().to_owned();
+                let _ = sender.send(query);
+
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                // The client hangs up early on bodies it refuses to read.
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        (url, receiver)
+    }
+
+    fn compact_body(peers: &[[u8; 6]]) -> Vec<u8> {
+        let mut body = format!("d8:intervali1800e5:peers{}:", peers.len() * 6).into_bytes();
+        body.extend(peers.concat());
+        body.push(b'e');
+        body
+    }
+
+    /// A URL nothing listens on, so announcing to it fails right away.
+    async fn dead_tracker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/announce", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_tiers_fall_back_and_merge_peers() {
+        let body = compact_body(&[[10, 0, 0, 1, 0, 80], [10, 0, 0, 2, 0, 80]]);
+        let (first, _) = spawn_http_tracker(body).await;
+        let body = compact_body(&[[10, 0, 0, 2, 0, 80], [10, 0, 0, 3, 0, 80]]);
+        let (second, _) = spawn_http_tracker(body).await;
+        let dead = dead_tracker().await;
+
+        let mut trackers = TrackerList::new(vec![
+            vec![dead.clone(), first.clone()],
+            vec![dead.clone()],
+            vec![second],
+        ]);
+        let tracker = Tracker::new(100);
+        let peers = trackers.get_peers(&tracker, &[0; 20]).await.unwrap();
+
+        let peer = |last| SocketAddr::from(([10, 0, 0, last], 80));
+        assert_eq!(peers, [peer(1), peer(2), peer(3)]);
+        // The working tracker is asked first next time.
+        assert_eq!(trackers.tiers[0], [first, dead.clone()]);
+
+        let mut trackers = TrackerList::new(vec![vec![dead]]);
+        assert!(matches!(
+            trackers.get_peers(&tracker, &[0; 20]).await,
+            Err(crate::Error::Network(_))
+        ));
+    }
+
+    /// A `udp://` tracker that answers every request and counts the connects.
+    async fn spawn_udp_tracker(connects: Arc<AtomicUsize>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 2048];
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                // Action and transaction id, echoed back.
+                let mut response = buffer[8..16].to_vec();
+                match buffer[11] {
+                    0 => {
+                        connects.fetch_add(1, Ordering::SeqCst);
+                        response.extend_from_slice(&7u64.to_be_bytes());
+                    }
+                    1 => {
+                        for value in [1800u32, 0, 1] {
+                            response.extend_from_slice(&value.to_be_bytes());
+                        }
+                        response.extend_from_slice(&[10, 0, 0, 1, 0, 80]);
+                    }
+                    _ => response.extend(vec![0; (length - 16) / 20 * 12]),
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_udp_connection_is_reused() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let url = spawn_udp_tracker(Arc::clone(&connects)).await;
+        let mut trackers = TrackerList::new(vec![vec![url]]);
+
+        let tracker = Tracker::new(100);
+        for _ in 0..2 {
+            let peers = trackers.get_peers(&tracker, &[0; 20]).await.unwrap();
+            assert_eq!(peers, [SocketAddr::from(([10, 0, 0, 1], 80))]);
+        }
+        let stats = trackers.scrape(&[[0; 20]]).await.unwrap();
+        assert_eq!(stats, [ScrapeStats::default()]);
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_lifecycle() {
+        let body = b"d8:completei5e10:incompletei3e8:intervali900e12:min intervali1200e\
+            5:peers6:\x0a\x00\x00\x01\x00\x5010:tracker id3:abc\
+            15:warning message4:slowe";
+        let (url, mut requests) = spawn_http_tracker(body.to_vec()).await;
+        let torrent = Torrent::new(
+            url,
+            crate::torrent::TorrentInfo {
+                length: Some(100),
+                name: "file".to_owned(),
+                piece_length: 100,
+                pieces: vec![0; 20],
+                files: None,
+                private: None,
+            },
+        )
+        .unwrap();
+
+        let mut announcer = Announcer::new(&torrent, 7000);
+        assert!(!announcer.has_announced());
+        let transfer = Transfer {
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+        };
+        let response = announcer
+            .announce(Some(Event::Started), transfer)
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            AnnounceResponse {
+                interval: Duration::from_secs(900),
+                min_interval: Some(Duration::from_secs(1200)),
+                tracker_id: Some("abc".to_owned()),
+                complete: Some(5),
+                incomplete: Some(3),
+                warning: Some("slow".to_owned()),
+                peers: vec![SocketAddr::from(([10, 0, 0, 1], 80))],
+            }
+        );
+        // The minimum interval wins over a shorter regular one.
+        assert!(announcer.until_next() > Duration::from_secs(1190));
+
+        let query = requests.recv().await.unwrap();
+        assert!(query.contains("port=7000&uploaded=0&downloaded=0&left=100"));
+        assert!(query.contains("event=started") && !query.contains("trackerid"));
+
+        let transfer = Transfer {
+            uploaded: 10,
+            downloaded: 100,
+            left: 0,
+        };
+        announcer
+            .announce(Some(Event::Completed), transfer)
+            .await
+            .unwrap();
+        let query = requests.recv().await.unwrap();
+        assert!(query.contains("uploaded=10&downloaded=100&left=0"));
+        assert!(query.contains("event=completed&trackerid=abc"));
+
+        announcer.announce(None, transfer).await.unwrap();
+        assert!(!requests.recv().await.unwrap().contains("event"));
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_and_ipv6_peers() {
+        let mut body = b"d5:peersl\
+            d2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti80ee\
+            d2:ip11:example.com4:porti80ee\
+            d2:ip3:::14:porti81ee\
+            e6:peers618:"
+            .to_vec();
+        body.extend(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        body.extend(6881u16.to_be_bytes());
+        body.push(b'e');
+        let (url, _) = spawn_http_tracker(body).await;
+
+        let response = Tracker::new(100).announce(&url, &[0; 20]).await.unwrap();
+        assert_eq!(
+            response.peers,
+            [
+                "10.0.0.1:80".parse::<SocketAddr>().unwrap(),
+                "[::1]:81".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+
+        let peers = parse_compact_peers(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(peers, ["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_errors() {
+        async fn get_peers(status: &'static str, body: &[u8]) -> Result<Vec<SocketAddr>, Error> {
+            let (url, _) = spawn_http_tracker_with_status(status, body.to_vec()).await;
+            TrackerList::new(vec![vec![url]])
+                .get_peers(&Tracker::new(100), &[0; 20])
+                .await
+        }
+
+        let refused = b"d14:failure reason20:unregistered torrente";
+        assert!(matches!(
+            get_peers("200 OK", refused).await,
+            Err(Error::TrackerFailure(reason)) if reason == "unregistered torrent"
+        ));
+        assert!(matches!(
+            get_peers("403 Forbidden", refused).await,
+            Err(Error::TrackerFailure(_))
+        ));
+        assert!(matches!(
+            get_peers("404 Not Found", b"no such tracker\n").await,
+            Err(Error::TrackerStatus(404, body)) if body == "no such tracker"
+        ));
+        assert!(matches!(
+            get_peers("200 OK", b"<html></html>").await,
+            Err(Error::InvalidTrackerResponse(_))
+        ));
+        assert!(matches!(
+            get_peers("200 OK", b"d8:intervali60ee").await,
+            Err(Error::InvalidTrackerResponse(_))
+        ));
+        assert!(matches!(
+            get_peers("200 OK", b"d5:peers0:15:warning message4:slowe").await,
+            Err(Error::TrackerWarning(warning)) if warning == "slow"
+        ));
+        assert!(matches!(
+            get_peers("200 OK", b"d5:peers0:e").await,
+            Err(Error::NoPeers)
+        ));
+
+        let mut deep = b"d5:peers0:7:unknown".to_vec();
+        deep.extend(vec![b'l'; 100_000]);
+        deep.extend(vec![b'e'; 100_000]);
+        deep.push(b'e');
+        assert!(matches!(
+            get_peers("200 OK", &deep).await,
+            Err(Error::InvalidTrackerResponse(_))
+        ));
+        let mut huge = b"d5:peers0:7:padding".to_vec();
+        huge.extend(format!("{}:", 2 * MAX_RESPONSE_SIZE).into_bytes());
+        huge.extend(vec![b'x'; 2 * MAX_RESPONSE_SIZE]);
+        huge.push(b'e');
+        assert!(matches!(
+            get_peers("200 OK", &huge).await,
+            Err(Error::InvalidTrackerResponse(reason)) if reason.contains("larger than")
+        ));
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(
+            scrape_url("http://tracker/announce").as_deref(),
+            Some("http://tracker/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://tracker/x/announce.php?key=1").as_deref(),
+            Some("http://tracker/x/scrape.php?key=1")
+        );
+        assert_eq!(scrape_url("http://tracker/a"), None);
+        assert_eq!(scrape_url("http://tracker/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_http_scrape() {
+        let known = [0xff; 20];
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend(known);
+        body.extend(b"d8:completei5e10:downloadedi10e10:incompletei3eeee");
+        let (url, mut requests) = spawn_http_tracker(body).await;
+
+        let stats = scrape(&url, &[known, [b'a'; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            [
+                ScrapeStats {
+                    complete: 5,
+                    incomplete: 3,
+                    downloaded: 10,
+                },
+                ScrapeStats::default(),
+            ]
+        );
+
+        let query = requests.recv().await.unwrap();
+        assert_eq!(
+            query,
+            format!(
+                "/scrape?info_hash={}&info_hash={}",
+                "%ff".repeat(20),
+                "a".repeat(20)
+            )
+        );
+
+        assert!(matches!(
+            scrape("http://tracker/tracker", &[known]).await,
+            Err(crate::Error::ScrapeNotSupported(_))
+        ));
     }
 }
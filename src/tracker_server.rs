@@ -0,0 +1,517 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{bencode::Bencode, encoder::Encoder, random::shuffle, Error};
+
+/// Largest request head accepted. Announces are a few hundred bytes, scrapes of many
+/// torrents a few kilobytes.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// Tracker settings.
+#[derive(Debug, Clone)]
+pub(crate) struct TrackerServerConfig {
+    /// How often clients are told to re-announce.
+    pub(crate) interval: Duration,
+    /// Peers that haven't announced for this long are dropped from their swarm.
+    pub(crate) peer_timeout: Duration,
+    /// Peers returned when the client doesn't send `numwant`.
+    pub(crate) default_numwant: usize,
+    /// Upper bound on the peers returned, whatever the client asks for.
+    pub(crate) max_numwant: usize,
+    /// Info hashes of the only torrents tracked, or `None` to track any torrent.
+    pub(crate) whitelist: Option<HashSet<[u8; 20]>>,
+    /// Clients that don't send a complete request within this time are disconnected.
+    pub(crate) request_timeout: Duration,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30 * 60),
+            peer_timeout: Duration::from_secs(2 * 30 * 60),
+            default_numwant: 50,
+            max_numwant: 200,
+            whitelist: None,
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct SwarmPeer {
+    peer_id: Vec<u8>,
+    left: u64,
+    last_seen: Instant,
+}
+
+/// The peers of one torrent, keyed by the address other peers reach them at.
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<SocketAddr, SwarmPeer>,
+    /// Number of `completed` events received.
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn expire(&mut self, peer_timeout: Duration) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+    }
+
+    fn seeders(&self) -> usize {
+        self.peers.values().filter(|peer| peer.left == 0).count()
+    }
+
+    fn stats(&self) -> Bencode {
+        let seeders = self.seeders();
+        Bencode::dict([
+            ("complete", Bencode::Int(seeders as i64)),
+            ("downloaded", Bencode::Int(self.downloaded as i64)),
+            (
+                "incomplete",
+                Bencode::Int((self.peers.len() - seeders) as i64),
+            ),
+        ])
+    }
+}
+
+/// An HTTP tracker answering announces and scrapes, with its swarms kept in memory.
+pub(crate) struct TrackerServer {
+    config: TrackerServerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl TrackerServer {
+    pub(crate) fn new(config: TrackerServerConfig) -> Self {
+        Self {
+            config,
+            swarms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serves clients until an error occurs on the listener. Peers that stopped announcing
+    /// are expired every `peer_timeout`, in every swarm.
+    pub(crate) async fn listen(self: Arc<Self>, listener: TcpListener) -> Result<(), Error> {
+        tokio::select! {
+            result = Arc::clone(&self).accept(listener) => result,
+            _ = self.expire_periodically() => Ok(()),
+        }
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener) -> Result<(), Error> {
+        loop {
+            let (stream, client_addr) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(stream, client_addr.ip()).await {
+                    eprintln!("Client {} failed: {}", client_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn expire_periodically(&self) {
+        // `interval` panics on a zero period.
+        let period = self.config.peer_timeout.max(Duration::from_millis(1));
+        let mut rounds = tokio::time::interval(period);
+        loop {
+            rounds.tick().await;
+            self.expire(&mut self.swarms.lock().unwrap());
+        }
+    }
+
+    /// Drops expired peers, and swarms left without peers.
+    fn expire(&self, swarms: &mut HashMap<[u8; 20], Swarm>) {
+        swarms.retain(|_, swarm| {
+            swarm.expire(self.config.peer_timeout);
+            !swarm.peers.is_empty()
+        });
+    }
+
+    async fn serve(&self, mut stream: TcpStream, client_ip: IpAddr) -> Result<(), Error> {
+        let head =
+            tokio::time::timeout(self.config.request_timeout, read_request_head(&mut stream))
+                .await
+                .map_err(io::Error::from)??;
+        let request_line = head.lines().next().unwrap_or_default();
+        let (status, body) = match request_line.split(' ').collect::<Vec<_>>()[..] {
+            ["GET", target, _] => self.handle(target, client_ip)?,
+            _ => ("400 Bad Request", b"Bad request".to_vec()),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        Ok(())
+    }
+
+    /// Answers a request for `target`, the path and query of the request line. Requests
+    /// the tracker refuses get a `failure reason` with a 200 status, as clients expect.
+    fn handle(&self, target: &str, client_ip: IpAddr) -> Result<(&'static str, Vec<u8>), Error> {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let response = match path.rsplit('/').next() {
+            Some("announce") => {
+                parse_query(query).and_then(|params| self.announce(&params, client_ip))
+            }
+            Some("scrape") => parse_query(query).and_then(|params| self.scrape(&params)),
+            _ => return Ok(("404 Not Found", b"Not found".to_vec())),
+        };
+        let response = response.unwrap_or_else(|reason| {
+            Bencode::dict([("failure reason", Bencode::Bytes(reason.into_bytes()))])
+        });
+        Ok(("200 OK", Encoder::encode(&response)?))
+    }
+
+    fn announce(&self, params: &[(String, Vec<u8>)], client_ip: IpAddr) -> Result<Bencode, String> {
+        let info_hash = parse_info_hash(param(params, "info_hash"))?;
+        if !self.is_allowed(&info_hash) {
+            return Err("Torrent is not tracked here".to_owned());
+        }
+        let port: u16 = number(params, "port")?.ok_or("Missing port")?;
+        let left: u64 = number(params, "left")?.ok_or("Missing left")?;
+        let compact = number::<u8>(params, "compact")? != Some(0);
+        let numwant = number(params, "numwant")?
+            .unwrap_or(self.config.default_numwant)
+            .min(self.config.max_numwant);
+        let peer_id = param(params, "peer_id").unwrap_or_default().to_vec();
+        // Clients behind NAT or on another interface can't be reached at a self-reported
+        // `ip`, so the address the request came from is used.
+        let peer_addr = SocketAddr::new(client_ip.to_canonical(), port);
+
+        let mut swarms = self.swarms.lock().unwrap();
+        // Taken out and only put back with peers, so a `stopped` event or an announce
+        // for a swarm whose peers all expired doesn't leave an empty swarm behind.
+        let mut swarm = swarms.remove(&info_hash).unwrap_or_default();
+        swarm.expire(self.config.peer_timeout);
+        match param(params, "event") {
+            Some(b"stopped") => {
+                swarm.peers.remove(&peer_addr);
+            }
+            event => {
+                if event == Some(b"completed") {
+                    swarm.downloaded += 1;
+                }
+                let peer = SwarmPeer {
+                    peer_id,
+                    left,
+                    last_seen: Instant::now(),
+                };
+                swarm.peers.insert(peer_addr, peer);
+            }
+        }
+
+        let mut others: Vec<(&SocketAddr, &SwarmPeer)> = swarm
+            .peers
+            .iter()
+            .filter(|(addr, _)| **addr != peer_addr)
+            .collect();
+        shuffle(&mut others);
+        others.truncate(numwant);
+
+        let seeders = swarm.seeders();
+        let mut response = BTreeMap::from([
+            (
+                b"interval".to_vec(),
+                Bencode::Int(self.config.interval.as_secs() as i64),
+            ),
+            (b"complete".to_vec(), Bencode::Int(seeders as i64)),
+            (
+                b"incomplete".to_vec(),
+                Bencode::Int((swarm.peers.len() - seeders) as i64),
+            ),
+        ]);
+        if compact {
+            let mut peers = Vec::new();
+            let mut peers6 = Vec::new();
+            for (addr, _) in others {
+                match addr {
+                    SocketAddr::V4(addr) => {
+                        peers.extend(addr.ip().octets());
+                        peers.extend(addr.port().to_be_bytes());
+                    }
+                    SocketAddr::V6(addr) => {
+                        peers6.extend(addr.ip().octets());
+                        peers6.extend(addr.port().to_be_bytes());
+                    }
+                }
+            }
+            if !peers6.is_empty() {
+                response.insert(b"peers6".to_vec(), Bencode::Bytes(peers6));
+            }
+            response.insert(b"peers".to_vec(), Bencode::Bytes(peers));
+        } else {
+            let peers = others
+                .into_iter()
+                .map(|(addr, peer)| {
+                    Bencode::dict([
+                        ("ip", Bencode::Bytes(addr.ip().to_string().into_bytes())),
+                        ("peer id", Bencode::Bytes(peer.peer_id.clone())),
+                        ("port", Bencode::Int(addr.port().into())),
+                    ])
+                })
+                .collect();
+            response.insert(b"peers".to_vec(), Bencode::List(peers));
+        }
+
+        if !swarm.peers.is_empty() {
+            swarms.insert(info_hash, swarm);
+        }
+        Ok(Bencode::Dict(response))
+    }
+
+    /// Reports the requested torrents, or every tracked torrent if none is given.
+    /// Torrents without a swarm are left out.
+    fn scrape(&self, params: &[(String, Vec<u8>)]) -> Result<Bencode, String> {
+        let info_hashes = params
+            .iter()
+            .filter(|(key, _)| key == "info_hash")
+            .map(|(_, value)| parse_info_hash(Some(value)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut swarms = self.swarms.lock().unwrap();
+        self.expire(&mut swarms);
+        let info_hashes = if info_hashes.is_empty() {
+            swarms.keys().copied().collect()
+        } else {
+            info_hashes
+        };
+
+        let mut files = BTreeMap::new();
+        for info_hash in info_hashes {
+            if let Some(swarm) = swarms.get(&info_hash) {
+                files.insert(info_hash.to_vec(), swarm.stats());
+            }
+        }
+        Ok(Bencode::dict([("files", Bencode::Dict(files))]))
+    }
+
+    fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        match &self.config.whitelist {
+            Some(whitelist) => whitelist.contains(info_hash),
+            None => true,
+        }
+    }
+}
+
+/// Reads up to the blank line ending the request head. Announces have no body.
+async fn read_request_head(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_SIZE {
+            return Err(Error::InvalidHttpRequest("request too large".to_owned()));
+        }
+        let length = stream.read(&mut buffer).await?;
+        if length == 0 {
+            return Err(Error::UnexpectedEOF);
+        }
+        head.extend_from_slice(&buffer[..length]);
+    }
+    String::from_utf8(head).map_err(|_| Error::InvalidUTF8)
+}
+
+/// Splits a query string into its percent-decoded parameters, keeping repeated keys.
+fn parse_query(query: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = String::from_utf8(percent_decode(key)?)
+                .map_err(|_| format!("Invalid parameter {}", key))?;
+            Ok((key, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> Result<Vec<u8>, String> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = input
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("Invalid percent-encoding in {}", input))?;
+                output.push(byte);
+                i += 3;
+            }
+            b'+' => {
+                output.push(b' ');
+                i += 1;
+            }
+            byte => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_slice())
+}
+
+fn number<T: FromStr>(params: &[(String, Vec<u8>)], key: &str) -> Result<Option<T>, String> {
+    param(params, key)
+        .map(|value| {
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| format!("Invalid {}", key))
+        })
+        .transpose()
+}
+
+fn parse_info_hash(value: Option<&[u8]>) -> Result<[u8; 20], String> {
+    value
+        .and_then(|value| value.try_into().ok())
+        .ok_or_else(|| "Invalid info_hash".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{self, Event, Tracker};
+
+    async fn spawn_tracker_server(config: TrackerServerConfig) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(Arc::new(TrackerServer::new(config)).listen(listener));
+        url
+    }
+
+    fn client(port: u16, left: u64) -> Tracker {
+        let mut client = Tracker::new(left);
+        client.port = port;
+        client
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let url = spawn_tracker_server(TrackerServerConfig::default()).await;
+        let info_hash = [7; 20];
+
+        let mut seeder = client(7001, 0);
+        seeder.event = Some(Event::Started);
+        let response = seeder.announce(&url, &info_hash).await.unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.interval, Duration::from_secs(30 * 60));
+
+        let leecher = client(7002, 100);
+        let response = leecher.announce(&url, &info_hash).await.unwrap();
+        assert_eq!(response.peers, ["127.0.0.1:7001".parse().unwrap()]);
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+
+        let mut leecher = client(7002, 0);
+        leecher.event = Some(Event::Completed);
+        leecher.announce(&url, &info_hash).await.unwrap();
+        let stats = tracker::scrape(&url, &[info_hash, [8; 20]]).await.unwrap();
+        assert_eq!(
+            stats[0],
+            tracker::ScrapeStats {
+                complete: 2,
+                incomplete: 0,
+                downloaded: 1,
+            }
+        );
+        assert_eq!(stats[1], tracker::ScrapeStats::default());
+
+        seeder.event = Some(Event::Stopped);
+        seeder.announce(&url, &info_hash).await.unwrap();
+        leecher.event = None;
+        let response = leecher.announce(&url, &info_hash).await.unwrap();
+        assert!(response.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_whitelist_and_expiry() {
+        let config = TrackerServerConfig {
+            peer_timeout: Duration::from_millis(100),
+            whitelist: Some(HashSet::from([[1; 20]])),
+            ..TrackerServerConfig::default()
+        };
+        let url = spawn_tracker_server(config).await;
+
+        assert!(matches!(
+            client(7001, 0).announce(&url, &[2; 20]).await,
+            Err(Error::TrackerFailure(_))
+        ));
+
+        client(7001, 0).announce(&url, &[1; 20]).await.unwrap();
+        let response = client(7002, 0).announce(&url, &[1; 20]).await.unwrap();
+        assert_eq!(response.peers.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let response = client(7002, 0).announce(&url, &[1; 20]).await.unwrap();
+        assert!(response.peers.is_empty());
+    }
+
+    #[test]
+    fn test_empty_swarms_are_dropped() {
+        let server = TrackerServer::new(TrackerServerConfig {
+            peer_timeout: Duration::ZERO,
+            ..TrackerServerConfig::default()
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let announce = "/announce?info_hash=%03%03%03%03%03%03%03%03%03%03%03%03%03%03%03%03%03%03%03%03&port=7001&left=0";
+
+        server
+            .handle(&format!("{announce}&event=stopped"), ip)
+            .unwrap();
+        assert!(server.swarms.lock().unwrap().is_empty());
+
+        server.handle(announce, ip).unwrap();
+        assert_eq!(server.swarms.lock().unwrap().len(), 1);
+        server.handle("/scrape", ip).unwrap();
+        assert!(server.swarms.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idle_clients_are_disconnected() {
+        let config = TrackerServerConfig {
+            request_timeout: Duration::from_millis(50),
+            ..TrackerServerConfig::default()
+        };
+        let url = spawn_tracker_server(config).await;
+        let addr = url
+            .trim_start_matches("http://")
+            .trim_end_matches("/announce");
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /announce").await.unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(stream.read(&mut buffer).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("info_hash=%ff%00a&numwant=5&event=&x+y=1").unwrap();
+        assert_eq!(param(&params, "info_hash"), Some(&[0xff, 0, b'a'][..]));
+        assert_eq!(number::<usize>(&params, "numwant"), Ok(Some(5)));
+        assert_eq!(param(&params, "event"), Some(&[][..]));
+        assert_eq!(param(&params, "x y"), Some(&b"1"[..]));
+        assert!(number::<u16>(&params, "info_hash").is_err());
+        assert!(parse_query("info_hash=%f").is_err());
+    }
+}
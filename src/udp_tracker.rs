@@ -0,0 +1,402 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{random::random_u64, Error};
+
+/// Magic constant identifying the UDP tracker protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+/// Trackers accept a connection id for this long after handing it out.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// First retransmission timeout, doubled on every retry as BEP 15 describes. BEP 15 starts
+/// at 15 seconds; a shorter start keeps a dead tracker from holding up the tier walk for
+/// minutes.
+const BASE_TIMEOUT: Duration = Duration::from_secs(5);
+/// BEP 15 allows 8 retries, over an hour of waiting. We give up after 35 seconds, about
+/// what an HTTP tracker gets.
+const MAX_RETRIES: u32 = 2;
+const MAX_PACKET_SIZE: usize = 2048;
+/// Info hashes per scrape request. BEP 15 puts the limit at about 74, more may make the
+/// response too large for one packet.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// The parameters of an announce, matching the fields of an HTTP announce.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UdpAnnounceRequest {
+    pub(crate) info_hash: [u8; 20],
+    pub(crate) peer_id: [u8; 20],
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) uploaded: u64,
+    /// 0: none, 1: completed, 2: started, 3: stopped.
+    pub(crate) event: u32,
+    pub(crate) port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UdpAnnounceResponse {
+    /// Seconds to wait before announcing again.
+    pub(crate) interval: u32,
+    pub(crate) leechers: u32,
+    pub(crate) seeders: u32,
+    /// Peers in compact form, 6 bytes each.
+    pub(crate) peers: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UdpScrapeResponse {
+    pub(crate) seeders: u32,
+    pub(crate) completed: u32,
+    pub(crate) leechers: u32,
+}
+
+/// A client for one `udp://` tracker. The connection id is kept across requests until
+/// it expires.
+pub(crate) struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port[/path]` URL and prepares a socket for it.
+    pub(crate) async fn connect(url: &str) -> Result<Self, Error> {
+        let host = url
+            .strip_prefix("udp://")
+            .and_then(|rest| rest.split('/').next())
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| Error::InvalidTrackerUrl(url.to_owned()))?;
+        let addr = tokio::net::lookup_host(host)
+            .await?
+            .next()
+            .ok_or_else(|| Error::InvalidTrackerUrl(url.to_owned()))?;
+        Self::connect_addr(addr).await
+    }
+
+    async fn connect_addr(addr: SocketAddr) -> Result<Self, Error> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    /// Whether the tracker is reached over IPv6, in which case it announces IPv6 peers.
+    pub(crate) fn is_ipv6(&self) -> bool {
+        self.socket.peer_addr().is_ok_and(|addr| addr.is_ipv6())
+    }
+
+    pub(crate) async fn announce(
+        &mut self,
+        request: &UdpAnnounceRequest,
+    ) -> Result<UdpAnnounceResponse, Error> {
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&request.info_hash);
+        body.extend_from_slice(&request.peer_id);
+        body.extend_from_slice(&request.downloaded.to_be_bytes());
+        body.extend_from_slice(&request.left.to_be_bytes());
+        body.extend_from_slice(&request.uploaded.to_be_bytes());
+        body.extend_from_slice(&request.event.to_be_bytes());
+        // Let the tracker use the address the packet came from.
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&(random_u64() as u32).to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        body.extend_from_slice(&request.port.to_be_bytes());
+
+        let payload = self.request(ACTION_ANNOUNCE, &body).await?;
+        if payload.len() < 12 {
+            return Err(Error::InvalidTrackerResponse(
+                "announce response too short".to_owned(),
+            ));
+        }
+        Ok(UdpAnnounceResponse {
+            interval: read_u32(&payload[0..4]),
+            leechers: read_u32(&payload[4..8]),
+            seeders: read_u32(&payload[8..12]),
+            peers: payload[12..].to_vec(),
+        })
+    }
+
+    /// Asks for the swarm statistics of several torrents, `MAX_SCRAPE_HASHES` per request.
+    pub(crate) async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<UdpScrapeResponse>, Error> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for info_hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let payload = self.request(ACTION_SCRAPE, &info_hashes.concat()).await?;
+            if payload.len() < info_hashes.len() * 12 {
+                return Err(Error::InvalidTrackerResponse(
+                    "scrape response too short".to_owned(),
+                ));
+            }
+            stats.extend(
+                payload
+                    .chunks_exact(12)
+                    .take(info_hashes.len())
+                    .map(|chunk| UdpScrapeResponse {
+                        seeders: read_u32(&chunk[0..4]),
+                        completed: read_u32(&chunk[4..8]),
+                        leechers: read_u32(&chunk[8..12]),
+                    }),
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Sends a request, connecting first if there is no valid connection id, and
+    /// retransmits with a doubling timeout until a matching response arrives.
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, Error> {
+        for attempt in 0..=self.max_retries {
+            let timeout = self.base_timeout * 2u32.pow(attempt);
+
+            let connection_id = match self.connection {
+                Some((id, since)) if since.elapsed() < CONNECTION_ID_LIFETIME => id,
+                _ => match self
+                    .transact(PROTOCOL_ID, ACTION_CONNECT, &[], timeout)
+                    .await?
+                {
+                    Some(payload) if payload.len() >= 8 => {
+                        let id = u64::from_be_bytes(payload[..8].try_into().unwrap());
+                        self.connection = Some((id, Instant::now()));
+                        id
+                    }
+                    Some(_) => {
+                        return Err(Error::InvalidTrackerResponse(
+                            "connect response too short".to_owned(),
+                        ))
+                    }
+                    None => continue,
+                },
+            };
+
+            match self.transact(connection_id, action, body, timeout).await {
+                Ok(Some(payload)) => return Ok(payload),
+                Ok(None) => {}
+                // The error may be about the connection id, get a fresh one next time.
+                Err(e) => {
+                    self.connection = None;
+                    return Err(e);
+                }
+            }
+        }
+        Err(Error::TrackerTimeout)
+    }
+
+    /// One round trip. Returns the response payload after the header, or `None` if no
+    /// response with our transaction id arrived in time.
+    async fn transact(
+        &self,
+        connection_id: u64,
+        action: u32,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let transaction_id = random_u64() as u32;
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        self.socket.send(&packet).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let received = tokio::time::timeout_at(deadline, self.socket.recv(&mut buffer)).await;
+            let Ok(length) = received else {
+                return Ok(None);
+            };
+            let response = &buffer[..length?];
+
+            // Late answers to earlier attempts and stray packets are dropped.
+            if response.len() < 8 || read_u32(&response[4..8]) != transaction_id {
+                continue;
+            }
+            let payload = response[8..].to_vec();
+            return match read_u32(&response[0..4]) {
+                ACTION_ERROR => Err(Error::TrackerFailure(
+                    String::from_utf8_lossy(&payload).into_owned(),
+                )),
+                response_action if response_action == action => Ok(Some(payload)),
+                response_action => Err(Error::InvalidTrackerResponse(format!(
+                    "expected action {}, got {}",
+                    action, response_action
+                ))),
+            };
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1234_5678_9abc_def0;
+
+    /// A stand-in tracker that ignores the first connect request, answers every other
+    /// request once with a wrong transaction id before the right one, and counts connects.
+    async fn spawn_tracker(connects: Arc<AtomicUsize>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; MAX_PACKET_SIZE];
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = &buffer[..length];
+                let connection_id = u64::from_be_bytes(request[0..8].try_into().unwrap());
+                let action = read_u32(&request[8..12]);
+                let transaction_id = read_u32(&request[12..16]);
+
+                let mut response = Vec::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        if connects.fetch_add(1, Ordering::SeqCst) == 0 {
+                            continue;
+                        }
+                        response.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                    }
+                    _ if connection_id != CONNECTION_ID => {
+                        let mut error = ACTION_ERROR.to_be_bytes().to_vec();
+                        error.extend_from_slice(&transaction_id.to_be_bytes());
+                        error.extend_from_slice(b"bad connection id");
+                        socket.send_to(&error, from).await.unwrap();
+                        continue;
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(length, 98);
+                        let port = u16::from_be_bytes([request[96], request[97]]);
+                        for value in [1800u32, 2, 5] {
+                            response.extend_from_slice(&value.to_be_bytes());
+                        }
+                        response.extend_from_slice(&[10, 0, 0, 1]);
+                        response.extend_from_slice(&port.to_be_bytes());
+                    }
+                    ACTION_SCRAPE => {
+                        for (i, _) in request[16..].chunks(20).enumerate() {
+                            for value in [i as u32, 7, 3] {
+                                response.extend_from_slice(&value.to_be_bytes());
+                            }
+                        }
+                    }
+                    _ => unreachable!("unknown action {}", action),
+                }
+
+                for id in [transaction_id.wrapping_add(1), transaction_id] {
+                    let mut packet = action.to_be_bytes().to_vec();
+                    packet.extend_from_slice(&id.to_be_bytes());
+                    packet.extend_from_slice(&response);
+                    socket.send_to(&packet, from).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let addr = spawn_tracker(Arc::clone(&connects)).await;
+        let mut tracker = UdpTracker::connect(&format!("udp://{}/announce", addr))
+            .await
+            .unwrap();
+        tracker.base_timeout = Duration::from_millis(50);
+
+        let request = UdpAnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            downloaded: 0,
+            left: 100,
+            uploaded: 0,
+            event: 2,
+            port: 6881,
+        };
+        let response = tracker.announce(&request).await.unwrap();
+        assert_eq!(
+            response,
+            UdpAnnounceResponse {
+                interval: 1800,
+                leechers: 2,
+                seeders: 5,
+                peers: vec![10, 0, 0, 1, 0x1a, 0xe1],
+            }
+        );
+
+        // The cached connection id is reused, the dropped connect was retransmitted.
+        let stats = tracker.scrape(&[[1; 20], [3; 20]]).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[1],
+            UdpScrapeResponse {
+                seeders: 1,
+                completed: 7,
+                leechers: 3
+            }
+        );
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        // An expired connection id makes the tracker answer with an error.
+        tracker.connection = Some((42, Instant::now()));
+        assert!(matches!(
+            tracker.announce(&request).await,
+            Err(Error::TrackerFailure(message)) if message == "bad connection id"
+        ));
+
+        // The rejected id is dropped, the next request connects again.
+        assert!(tracker.announce(&request).await.is_ok());
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+        // Large scrapes are split across several requests.
+        let info_hashes: Vec<[u8; 20]> = (0..100).map(|i| [i; 20]).collect();
+        let stats = tracker.scrape(&info_hashes).await.unwrap();
+        assert_eq!(stats.len(), 100);
+        assert_eq!(
+            stats[MAX_SCRAPE_HASHES - 1].seeders,
+            MAX_SCRAPE_HASHES as u32 - 1
+        );
+        assert_eq!(stats[MAX_SCRAPE_HASHES].seeders, 0);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_retries() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = UdpTracker::connect_addr(silent.local_addr().unwrap())
+            .await
+            .unwrap();
+        tracker.base_timeout = Duration::from_millis(10);
+
+        let started = Instant::now();
+        assert!(matches!(
+            tracker.scrape(&[[0; 20]]).await,
+            Err(Error::TrackerTimeout)
+        ));
+        // 10 + 20 + 40 milliseconds of waiting.
+        assert!(started.elapsed() >= Duration::from_millis(70));
+    }
+}